@@ -0,0 +1,56 @@
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+use anyhow::{Result, Error};
+use zip::ZipArchive;
+
+const WORKBOOK_EXTENSIONS: [&str; 7] = ["xlsx", "xlsm", "xlam", "xlsb", "xls", "xla", "ods"];
+
+fn has_extension(path: &str, extensions: &[&str]) -> bool {
+    Path::new(path)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| extensions.iter().any(|e| ext.eq_ignore_ascii_case(e)))
+        .unwrap_or(false)
+}
+
+// macOS metadata (the "__MACOSX" folder and "._" resource forks) and Office lock files ("~$") are not workbooks
+fn is_metadata(name: &str) -> bool {
+    name.split('/').any(|part| part == "__MACOSX")
+        || name.rsplit('/').next().is_some_and(|file_name| file_name.starts_with("._") || file_name.starts_with("~$"))
+}
+
+/// Returns true if the path points to a zip archive that should be treated as a container of workbooks.
+pub fn is_archive(path: &str) -> bool {
+    has_extension(path, &["zip"])
+}
+
+/// Lists the workbook members of a zip archive, skipping directories, non-workbook files, macOS metadata and Office lock files.
+pub fn list_workbooks(archive_path: &str) -> Result<Vec<String>, Error> {
+    let file = File::open(archive_path)?;
+    let archive = ZipArchive::new(BufReader::new(file))?;
+    let members = archive.file_names()
+        .filter(|name| !name.ends_with('/') && has_extension(name, &WORKBOOK_EXTENSIONS) && !is_metadata(name))
+        .map(|name| name.to_string())
+        .collect::<Vec<String>>();
+    Ok(members)
+}
+
+/// Reads a single archive member into memory, without extracting it to disk.
+pub fn read_member(archive_path: &str, member: &str) -> Result<Vec<u8>, Error> {
+    let file = File::open(archive_path)?;
+    let mut archive = ZipArchive::new(BufReader::new(file))?;
+    let mut zip_file = archive.by_name(member)?;
+    let mut data = Vec::with_capacity(zip_file.size() as usize);
+    zip_file.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// Builds the result key for an archive member, e.g. "bundle.zip/member.xlsx".
+pub fn member_key(archive_path: &str, member: &str) -> String {
+    let archive_name = Path::new(archive_path)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default();
+    format!("{}/{}", archive_name, member)
+}
@@ -0,0 +1,118 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use anyhow::{Result, Error};
+use serde_json::Value;
+
+// Written in the cache directory, so that clear_cache never deletes from a folder the cache did not create
+const MARKER: &str = ".sheet_excavator_cache";
+
+/// Stable 64-bit FNV-1a hash, rendered as hex. Used for cache keys, so it must not change between runs.
+pub fn hash_bytes(bytes: &[u8]) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    format!("{:016x}", hash)
+}
+
+/// Hash of the extraction details, so that changing the spec invalidates cached results.
+pub fn spec_hash(extraction_details: &[Value]) -> Result<String, Error> {
+    let spec = serde_json::to_string(extraction_details)?;
+    Ok(hash_bytes(spec.as_bytes()))
+}
+
+// Bumped when the layout of cache entries changes
+const CACHE_FORMAT: u32 = 1;
+
+// The folder of a spec in the cache. The crate version and the cache format are part of the key, so that results
+// cached by a version that extracted differently are not served after an upgrade.
+fn cache_key(extraction_details: &[Value]) -> Result<String, Error> {
+    let spec = serde_json::to_string(extraction_details)?;
+    let key = format!("{}\n{}\n{}", env!("CARGO_PKG_VERSION"), CACHE_FORMAT, spec);
+    Ok(hash_bytes(key.as_bytes()))
+}
+
+/// On-disk cache of per-file extraction results, keyed by file content hash, spec hash and crate version.
+pub struct Cache {
+    dir: PathBuf,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+impl Cache {
+    pub fn new(cache_dir: &str, extraction_details: &[Value]) -> Result<Self, Error> {
+        let dir = Path::new(cache_dir).join(cache_key(extraction_details)?);
+        fs::create_dir_all(&dir)?;
+        fs::write(Path::new(cache_dir).join(MARKER), "")?;
+        Ok(Cache { dir, hits: AtomicUsize::new(0), misses: AtomicUsize::new(0) })
+    }
+
+    fn entry_path(&self, content_hash: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", content_hash))
+    }
+
+    /// Returns the cached result for the given content hash, counting the lookup as a hit or miss.
+    pub fn get(&self, content_hash: &str) -> Option<Value> {
+        let cached = fs::read_to_string(self.entry_path(content_hash))
+            .ok()
+            .and_then(|contents| serde_json::from_str::<Value>(&contents).ok());
+        match cached {
+            Some(_) => self.hits.fetch_add(1, Ordering::Relaxed),
+            None => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+        cached
+    }
+
+    pub fn put(&self, content_hash: &str, value: &Value) -> Result<(), Error> {
+        // Write to a temporary file first, so that concurrent runs never read a partial entry
+        let path = self.entry_path(content_hash);
+        let tmp_path = path.with_extension("json.tmp");
+        fs::write(&tmp_path, serde_json::to_string(value)?)?;
+        fs::rename(&tmp_path, &path)?;
+        Ok(())
+    }
+
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> usize {
+        self.misses.load(Ordering::Relaxed)
+    }
+}
+
+// Cache entries and spec folders are named after a hash
+fn is_hash(name: &str) -> bool {
+    name.len() == 16 && name.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Removes the cached results of the cache directory. Only the spec folders and result files written by the cache
+/// are removed, and only from a directory with the cache marker, so a mistaken path leaves other files alone.
+pub fn clear_cache(cache_dir: &str) -> Result<(), Error> {
+    let path = Path::new(cache_dir);
+    if !path.exists() {
+        return Ok(());
+    }
+    if !path.join(MARKER).is_file() {
+        return Err(Error::msg(format!("'{}' is not a cache directory", cache_dir)));
+    }
+    for entry in fs::read_dir(path)? {
+        let dir = entry?.path();
+        if !dir.is_dir() || !dir.file_name().and_then(|name| name.to_str()).is_some_and(is_hash) {
+            continue;
+        }
+        for file in fs::read_dir(&dir)? {
+            let file = file?.path();
+            let name = file.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+            let stem = name.strip_suffix(".json").or_else(|| name.strip_suffix(".json.tmp"));
+            if file.is_file() && stem.is_some_and(is_hash) {
+                fs::remove_file(&file)?;
+            }
+        }
+        // A spec folder that still holds other files is kept
+        let _ = fs::remove_dir(&dir);
+    }
+    Ok(())
+}
@@ -4,12 +4,16 @@ use serde_json::to_string;
 use tokio::runtime;
 mod parallel;
 mod read_excel;
-use parallel::process_files;
+mod archive;
+mod cache;
+mod manifest;
+mod templates;
+use parallel::{process_files, fingerprint_files};
 mod utils; // Import the utils module
 use utils::pylist_to_json; // Import the conversion function
 
 #[pyfunction]
-fn excel_extract(_py: Python<'_>, file_paths: &Bound<'_, PyList>, extraction_details: &Bound<'_, PyList>, num_workers: Option<usize>) -> PyResult<String> {
+fn excel_extract(_py: Python<'_>, file_paths: &Bound<'_, PyList>, extraction_details: &Bound<'_, PyList>, num_workers: Option<usize>, cache_dir: Option<String>, manifest_path: Option<String>) -> PyResult<String> {
     let file_paths: Vec<String> = file_paths.iter().map(|p| {
         p.extract::<String>()
             .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(format!("Error extracting string: {}", e)))
@@ -17,10 +21,17 @@ fn excel_extract(_py: Python<'_>, file_paths: &Bound<'_, PyList>, extraction_det
     
     let extraction_details_serde = pylist_to_json(extraction_details)?;
     let rt = runtime::Runtime::new().unwrap();
-    let results = rt.block_on(async {
-        process_files(file_paths, extraction_details_serde, num_workers.unwrap_or(5)).await
+    let (results, manifest) = rt.block_on(async {
+        process_files(file_paths, extraction_details_serde, num_workers.unwrap_or(5), cache_dir).await
     }).map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(format!("Error processing files: {}", e)))?;
     
+    if let Some(manifest_path) = manifest_path {
+        let manifest_string = to_string(&manifest.to_value())
+            .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(format!("Error converting manifest to JSON string: {}", e)))?;
+        std::fs::write(&manifest_path, manifest_string)
+            .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(format!("Error writing manifest: {}", e)))?;
+    }
+
     // Serialize the entire Map<String, Value> into one JSON string
     let json_string = to_string(&results)
         .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(format!("Error converting results to JSON string: {}", e)))?;
@@ -28,9 +39,60 @@ fn excel_extract(_py: Python<'_>, file_paths: &Bound<'_, PyList>, extraction_det
     Ok(json_string)
 }
 
+#[pyfunction]
+fn detect_templates(_py: Python<'_>, file_paths: &Bound<'_, PyList>, templates: Option<&Bound<'_, PyList>>, num_workers: Option<usize>, threshold: Option<f64>) -> PyResult<String> {
+    let file_paths: Vec<String> = file_paths.iter().map(|p| {
+        p.extract::<String>()
+            .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(format!("Error extracting string: {}", e)))
+    }).collect::<PyResult<Vec<String>>>()?;
+
+    // Known templates are given as {"name": ..., "file": ...} dictionaries pointing to a reference workbook
+    let templates_serde = match templates {
+        Some(templates) => pylist_to_json(templates)?,
+        None => Vec::new(),
+    };
+    let mut template_names = Vec::new();
+    let mut template_paths = Vec::new();
+    for template in &templates_serde {
+        let name = template.get("name").and_then(|v| v.as_str())
+            .ok_or_else(|| pyo3::exceptions::PyValueError::new_err("Missing 'name' in template"))?;
+        let file = template.get("file").and_then(|v| v.as_str())
+            .ok_or_else(|| pyo3::exceptions::PyValueError::new_err("Missing 'file' in template"))?;
+        template_names.push(name.to_string());
+        template_paths.push(file.to_string());
+    }
+
+    let rt = runtime::Runtime::new().unwrap();
+    let num_workers = num_workers.unwrap_or(5);
+    let (fingerprints, template_fingerprints) = rt.block_on(async {
+        let fingerprints = fingerprint_files(file_paths, num_workers).await?;
+        let template_fingerprints = fingerprint_files(template_paths, num_workers).await?;
+        Ok::<_, anyhow::Error>((fingerprints, template_fingerprints))
+    }).map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(format!("Error fingerprinting files: {}", e)))?;
+
+    let mut known_templates = Vec::new();
+    for (name, (_, fingerprint)) in template_names.into_iter().zip(template_fingerprints) {
+        let fingerprint = fingerprint
+            .ok_or_else(|| pyo3::exceptions::PyRuntimeError::new_err(format!("Error reading template '{}'", name)))?;
+        known_templates.push((name, fingerprint));
+    }
+
+    let detected = templates::detect_templates(fingerprints, known_templates, threshold.unwrap_or(0.8));
+    to_string(&detected)
+        .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(format!("Error converting results to JSON string: {}", e)))
+}
+
+#[pyfunction]
+fn clear_cache(cache_dir: String) -> PyResult<()> {
+    cache::clear_cache(&cache_dir)
+        .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(format!("Error clearing cache: {}", e)))
+}
+
 #[pymodule]
 fn sheet_excavator(_py: Python<'_>, m: &Bound<'_, PyModule>) -> PyResult<()> {
     // Register the excel_extract function in the Python module
     m.add_function(wrap_pyfunction!(excel_extract, m)?)?;
+    m.add_function(wrap_pyfunction!(detect_templates, m)?)?;
+    m.add_function(wrap_pyfunction!(clear_cache, m)?)?;
     Ok(())
 }
\ No newline at end of file
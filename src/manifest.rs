@@ -0,0 +1,180 @@
+use serde_json::{Map, Value};
+
+fn strings_to_value(strings: &[String]) -> Value {
+    Value::Array(strings.iter().cloned().map(Value::String).collect())
+}
+
+fn value_to_strings(value: Option<&Value>) -> Vec<String> {
+    value
+        .and_then(Value::as_array)
+        .map(|arr| arr.iter().filter_map(Value::as_str).map(str::to_string).collect())
+        .unwrap_or_default()
+}
+
+/// Events recorded while extracting a single file, reported in the run manifest.
+#[derive(Default)]
+pub struct FileReport {
+    pub error: Option<String>,
+    pub skipped_sheets: Vec<String>,
+    /// Sheets checked by the "when" conditions of variant blocks without matching any of them.
+    pub unmatched_sheets: Vec<String>,
+    pub rule_events: Vec<Value>,
+    pub unsupported_functions: Vec<String>,
+    pub warnings: Vec<Value>,
+}
+
+impl FileReport {
+    pub fn failed(error: String) -> Self {
+        FileReport { error: Some(error), ..Default::default() }
+    }
+
+    /// Records a sheet (or a single extraction on a sheet) that was skipped or stopped by a rule.
+    pub fn add_rule_event(&mut self, sheet: &str, extraction: Option<&str>, action: &str) {
+        let mut map = Map::new();
+        map.insert("sheet".to_string(), Value::String(sheet.to_string()));
+        if let Some(extraction) = extraction {
+            map.insert("extraction".to_string(), Value::String(extraction.to_string()));
+        }
+        map.insert("action".to_string(), Value::String(action.to_string()));
+        self.rule_events.push(Value::Object(map));
+    }
+
+    /// Records a data-quality warning raised by an extraction, such as a repeated unique id.
+    pub fn add_warning(&mut self, sheet: &str, extraction: &str, message: &str) {
+        let mut map = Map::new();
+        map.insert("sheet".to_string(), Value::String(sheet.to_string()));
+        map.insert("extraction".to_string(), Value::String(extraction.to_string()));
+        map.insert("message".to_string(), Value::String(message.to_string()));
+        self.warnings.push(Value::Object(map));
+    }
+
+    pub fn to_value(&self) -> Value {
+        let mut map = Map::new();
+        map.insert("error".to_string(), self.error.clone().map(Value::String).unwrap_or(Value::Null));
+        map.insert("skipped_sheets".to_string(), strings_to_value(&self.skipped_sheets));
+        map.insert("unmatched_sheets".to_string(), strings_to_value(&self.unmatched_sheets));
+        map.insert("rule_events".to_string(), Value::Array(self.rule_events.clone()));
+        map.insert("unsupported_functions".to_string(), strings_to_value(&self.unsupported_functions));
+        map.insert("warnings".to_string(), Value::Array(self.warnings.clone()));
+        Value::Object(map)
+    }
+
+    pub fn from_value(value: &Value) -> Self {
+        FileReport {
+            error: value.get("error").and_then(Value::as_str).map(str::to_string),
+            skipped_sheets: value_to_strings(value.get("skipped_sheets")),
+            unmatched_sheets: value_to_strings(value.get("unmatched_sheets")),
+            rule_events: value.get("rule_events").and_then(Value::as_array).cloned().unwrap_or_default(),
+            unsupported_functions: value_to_strings(value.get("unsupported_functions")),
+            warnings: value.get("warnings").and_then(Value::as_array).cloned().unwrap_or_default(),
+        }
+    }
+}
+
+/// Summary of a run, covering every processed file.
+pub struct Manifest {
+    spec_hash: String,
+    files: Map<String, Value>,
+    successes: usize,
+    failures: usize,
+    skipped_sheets: Vec<Value>,
+    unmatched_sheets: Vec<Value>,
+    rule_events: Vec<Value>,
+    unsupported_functions: Vec<String>,
+    warnings: Vec<Value>,
+    cache: Option<(usize, usize)>,
+}
+
+impl Manifest {
+    pub fn new(spec_hash: String) -> Self {
+        Manifest {
+            spec_hash,
+            files: Map::new(),
+            successes: 0,
+            failures: 0,
+            skipped_sheets: Vec::new(),
+            unmatched_sheets: Vec::new(),
+            rule_events: Vec::new(),
+            unsupported_functions: Vec::new(),
+            warnings: Vec::new(),
+            cache: None,
+        }
+    }
+
+    pub fn contains_file(&self, key: &str) -> bool {
+        self.files.contains_key(key)
+    }
+
+    pub fn add_file(&mut self, key: String, report: FileReport, duration: f64, cached: bool) {
+        match report.error {
+            Some(_) => self.failures += 1,
+            None => self.successes += 1,
+        }
+        for sheet in &report.skipped_sheets {
+            self.skipped_sheets.push(sheet_event(&key, sheet));
+        }
+        for sheet in &report.unmatched_sheets {
+            self.unmatched_sheets.push(sheet_event(&key, sheet));
+        }
+        // Rule events and warnings are stored per file, the manifest lists them with the file key first
+        for event in &report.rule_events {
+            self.rule_events.push(file_event(&key, event));
+        }
+        for warning in &report.warnings {
+            self.warnings.push(file_event(&key, warning));
+        }
+        for function in &report.unsupported_functions {
+            if !self.unsupported_functions.contains(function) {
+                self.unsupported_functions.push(function.clone());
+            }
+        }
+
+        let mut file = Map::new();
+        file.insert("status".to_string(), Value::String(if report.error.is_some() { "failed" } else { "ok" }.to_string()));
+        file.insert("error".to_string(), report.error.map(Value::String).unwrap_or(Value::Null));
+        file.insert("duration".to_string(), Value::from(duration));
+        file.insert("cached".to_string(), Value::Bool(cached));
+        self.files.insert(key, Value::Object(file));
+    }
+
+    pub fn set_cache_stats(&mut self, hits: usize, misses: usize) {
+        self.cache = Some((hits, misses));
+    }
+
+    pub fn to_value(&self) -> Value {
+        let mut map = Map::new();
+        map.insert("spec_hash".to_string(), Value::String(self.spec_hash.clone()));
+        map.insert("total_files".to_string(), Value::from(self.files.len()));
+        map.insert("successes".to_string(), Value::from(self.successes));
+        map.insert("failures".to_string(), Value::from(self.failures));
+        map.insert("skipped_sheets".to_string(), Value::Array(self.skipped_sheets.clone()));
+        map.insert("unmatched_sheets".to_string(), Value::Array(self.unmatched_sheets.clone()));
+        map.insert("rule_events".to_string(), Value::Array(self.rule_events.clone()));
+        map.insert("unsupported_functions".to_string(), strings_to_value(&self.unsupported_functions));
+        map.insert("warnings".to_string(), Value::Array(self.warnings.clone()));
+        if let Some((hits, misses)) = self.cache {
+            let mut cache = Map::new();
+            cache.insert("hits".to_string(), Value::from(hits));
+            cache.insert("misses".to_string(), Value::from(misses));
+            map.insert("cache".to_string(), Value::Object(cache));
+        }
+        map.insert("files".to_string(), Value::Object(self.files.clone()));
+        Value::Object(map)
+    }
+}
+
+fn sheet_event(file: &str, sheet: &str) -> Value {
+    let mut map = Map::new();
+    map.insert("file".to_string(), Value::String(file.to_string()));
+    map.insert("sheet".to_string(), Value::String(sheet.to_string()));
+    Value::Object(map)
+}
+
+fn file_event(file: &str, event: &Value) -> Value {
+    let mut map = Map::new();
+    map.insert("file".to_string(), Value::String(file.to_string()));
+    if let Value::Object(event) = event {
+        map.extend(event.clone());
+    }
+    Value::Object(map)
+}
@@ -2,27 +2,139 @@ use tokio::sync::Semaphore; // Import Semaphore from tokio::sync
 use std::{sync::Arc, time::Instant}; // Import Arc for creating reference-counted pointers
 use futures::stream::{FuturesUnordered, StreamExt}; // Import FuturesUnordered and StreamExt for managing and polling futures
 use serde_json::{Value, Map}; // Import serde_json::Value
-use crate::read_excel::process_file;
+use crate::read_excel::{process_file, process_archive_member, process_bytes, validate_details};
 use anyhow::{Result, Error};// Use anyhow::Result for simplified error handling
 use crate::utils::conversions;
+use crate::archive;
+use crate::cache::{self, Cache};
+use crate::manifest::{FileReport, Manifest};
+use crate::templates::{self, Fingerprint};
 
-pub async fn process_files(file_paths: Vec<String>, extraction_details: Vec<Value>, num_workers: usize) -> Result<Map<String, Value>, Error> {
+/// A single workbook to process, either a file on disk or a member of a zip archive.
+enum Source {
+    File(String),
+    ArchiveMember { archive: String, member: String },
+}
+
+impl Source {
+    fn key(&self) -> String {
+        match self {
+            Source::File(path) => conversions::extract_filename(path),
+            Source::ArchiveMember { archive, member } => archive::member_key(archive, member),
+        }
+    }
+
+    fn file_path(&self) -> String {
+        match self {
+            Source::File(path) => path.clone(),
+            Source::ArchiveMember { archive, member } => format!("{}/{}", archive, member),
+        }
+    }
+
+    fn read(&self) -> Result<Vec<u8>, Error> {
+        match self {
+            Source::File(path) => Ok(std::fs::read(path)?),
+            Source::ArchiveMember { archive, member } => archive::read_member(archive, member),
+        }
+    }
+
+    async fn process(self, extraction_details: Vec<Value>) -> Result<(Value, FileReport), Error> {
+        match self {
+            Source::File(path) => process_file(path, extraction_details).await,
+            Source::ArchiveMember { archive, member } => process_archive_member(archive, member, extraction_details).await,
+        }
+    }
+
+    async fn fingerprint(self) -> Option<Fingerprint> {
+        match self {
+            Source::File(path) => templates::fingerprint_file(path).await,
+            Source::ArchiveMember { archive, member } => templates::fingerprint_archive_member(archive, member).await,
+        }
+    }
+}
+
+// Serves the source from the cache when possible, otherwise processes it and stores the result.
+// The boolean in the result tells whether the value was served from the cache.
+async fn process_cached(source: Source, extraction_details: Vec<Value>, cache: Option<Arc<Cache>>) -> Result<(Value, FileReport, bool), Error> {
+    let cache = match cache {
+        Some(cache) => cache,
+        None => return source.process(extraction_details).await.map(|(value, report)| (value, report, false)),
+    };
+    // The bytes read for the cache key are also the ones extracted, so each file is only read once
+    let data = match source.read() {
+        Ok(data) => data,
+        Err(_) => return source.process(extraction_details).await.map(|(value, report)| (value, report, false)),
+    };
+    let content_hash = cache::hash_bytes(&data);
+    if let Some(entry) = cache.get(&content_hash) {
+        if let (Some(mut value), Some(report)) = (entry.get("result").cloned(), entry.get("report")) {
+            // The same content may live under another path, so the filepath always reflects the current run
+            if let Value::Object(map) = &mut value {
+                map.insert("filepath".to_string(), Value::String(source.file_path()));
+            }
+            return Ok((value, FileReport::from_value(report), true));
+        }
+    }
+    let (value, report) = process_bytes(&source.file_path(), &data, &extraction_details)?;
+    if !value.is_null() {
+        let mut entry = Map::new();
+        entry.insert("result".to_string(), value.clone());
+        entry.insert("report".to_string(), report.to_value());
+        if let Err(err) = cache.put(&content_hash, &Value::Object(entry)) {
+            println!("Cache error: {}", err);
+        }
+    }
+    Ok((value, report, false))
+}
+
+// Expands zip archives into their workbook members, leaving other paths untouched
+fn collect_sources(file_paths: Vec<String>) -> Vec<Source> {
+    let mut sources = Vec::new();
+    for path in file_paths {
+        if !archive::is_archive(&path) {
+            sources.push(Source::File(path));
+            continue;
+        }
+        match archive::list_workbooks(&path) {
+            Ok(members) => sources.extend(members.into_iter().map(|member| Source::ArchiveMember {
+                archive: path.clone(),
+                member,
+            })),
+            Err(err) => println!("Error: {} :: {}", conversions::extract_filename(&path), err),
+        }
+    }
+    sources
+}
+
+pub async fn process_files(file_paths: Vec<String>, extraction_details: Vec<Value>, num_workers: usize, cache_dir: Option<String>) -> Result<(Map<String, Value>, Manifest), Error> {
     println!("Processing files!");
+    // Spec errors fail the run, only workbook and data errors are recorded against a file
+    validate_details(&extraction_details)?;
+    let mut manifest = Manifest::new(cache::spec_hash(&extraction_details)?);
+    let cache = match cache_dir {
+        Some(dir) => Some(Arc::new(Cache::new(&dir, &extraction_details)?)),
+        None => None,
+    };
     let semaphore = Arc::new(Semaphore::new(num_workers));
 
     let mut futures = FuturesUnordered::new();
     let start_time = Instant::now();
-    let total = file_paths.len();
+    let sources = collect_sources(file_paths);
+    let total = sources.len();
 
-    for (index, path_str) in file_paths.into_iter().enumerate() {
-        let path_str_clone = path_str.clone();
+    for (index, source) in sources.into_iter().enumerate() {
         let details_clone = extraction_details.clone();
         let sem_clone = semaphore.clone();
+        let cache_clone = cache.clone();
 
         let permit = sem_clone.acquire_owned().await.unwrap();
 
-        futures.push(tokio::spawn(async move {
-            let result = process_file(path_str_clone, details_clone).await;
+        let key = source.key();
+        let panic_key = key.clone();
+        let handle = tokio::spawn(async move {
+            let file_start_time = Instant::now();
+            let result = process_cached(source, details_clone, cache_clone).await;
+            let duration = file_start_time.elapsed().as_secs_f64();
             let files_left = total - (index + 1);
             let avg_time_per_file = if index > 0 {
                 start_time.elapsed().as_secs_f64() / (index+1) as f64
@@ -32,31 +144,67 @@ pub async fn process_files(file_paths: Vec<String>, extraction_details: Vec<Valu
             let estimated_time_left = avg_time_per_file * files_left as f64;
             println!("Progress: {}/{} files. Avg: {:.2}s. Time left: {:.2}s.", (index+1), total, avg_time_per_file, estimated_time_left);
             drop(permit);
-            result
-        }));
+            // An extraction error fails this file only, the run goes on with the other files
+            let (value, report, cached) = match result {
+                Ok(processed) => processed,
+                Err(err) => {
+                    println!("Error: {} :: {}", key, err);
+                    (Value::Null, FileReport::failed(err.to_string()), false)
+                },
+            };
+            (key, value, report, duration, cached)
+        });
+        // A panic while processing a file is recorded as a failure of that file
+        futures.push(async move {
+            handle.await.unwrap_or_else(|err| {
+                println!("Error: {} :: {}", panic_key, err);
+                (panic_key, Value::Null, FileReport::failed(format!("Panic while processing the file: {}", err)), 0.0, false)
+            })
+        });
     }
 
     let mut results = Map::new();
-    while let Some(res) = futures.next().await {
-        match res {
-            Ok(Ok(value)) => {
-                if let Some(file_path) = value.get("filepath").and_then(|v| v.as_str()) {
-                    let base_filename = conversions::extract_filename(file_path);
-                    let mut filename_key = base_filename.clone();
-                    let mut counter = 1;
-                    // Ensure the key is unique by appending a counter if needed
-                    while results.contains_key(&filename_key) {
-                        filename_key = format!("{}_{}", base_filename, counter);
-                        counter += 1;
-                    }
-                    results.insert(filename_key, value);
-                }
-            },
-            Ok(Err(e)) => return Err(e.into()), // Convert the inner error to the function's error type
-            Err(e) => return Err(anyhow::Error::new(e)), // Convert the JoinError to the function's error type
+    while let Some((base_filename, value, report, duration, cached)) = futures.next().await {
+        let mut filename_key = base_filename.clone();
+        let mut counter = 1;
+        // Ensure the key is unique by appending a counter if needed
+        while manifest.contains_file(&filename_key) {
+            filename_key = format!("{}_{}", base_filename, counter);
+            counter += 1;
+        }
+        if value.get("filepath").is_some() {
+            results.insert(filename_key.clone(), value);
         }
+        manifest.add_file(filename_key, report, duration, cached);
     }
 
     println!("All files processed. Total time: {:.2?}", start_time.elapsed());
-    Ok(results)
+    if let Some(cache) = &cache {
+        println!("Cache: {} hits, {} misses.", cache.hits(), cache.misses());
+        manifest.set_cache_stats(cache.hits(), cache.misses());
+    }
+    Ok((results, manifest))
+}
+
+/// Fingerprints the structure of every file, using the same worker pool and archive handling as `process_files`.
+/// Results keep the order of the input files, and are None for files that can't be opened.
+pub async fn fingerprint_files(file_paths: Vec<String>, num_workers: usize) -> Result<Vec<(String, Option<Fingerprint>)>, Error> {
+    let semaphore = Arc::new(Semaphore::new(num_workers));
+    let mut handles = Vec::new();
+
+    for source in collect_sources(file_paths) {
+        let permit = semaphore.clone().acquire_owned().await.unwrap();
+        handles.push(tokio::spawn(async move {
+            let key = source.key();
+            let fingerprint = source.fingerprint().await;
+            drop(permit);
+            (key, fingerprint)
+        }));
+    }
+
+    let mut fingerprints = Vec::new();
+    for handle in handles {
+        fingerprints.push(handle.await?);
+    }
+    Ok(fingerprints)
 }
\ No newline at end of file
@@ -1,8 +1,34 @@
-use calamine::{Reader, open_workbook_auto};
+use calamine::{Reader, Sheets, Xls, Xlsx, Xlsb, Ods, Data, Range, Table, open_workbook_auto, open_workbook_auto_from_rs, open_workbook_from_rs};
 use serde_json::{Map, Value};
 use anyhow::{Result, Error};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Cursor, Read, Seek};
 use std::iter::Iterator;
-use crate::utils::{conversions, manipulations, dataframe, single_cells, multirow_patterns, match_sheet_names};
+use std::path::Path;
+use crate::archive;
+use crate::manifest::FileReport;
+use crate::utils::{conversions, dataframe, single_cells, multirow_patterns, key_value, table_detection, excel_tables, matrix, repeating_blocks, annotations, controls, styles, match_sheet_names};
+use crate::utils::context::ExtractionContext;
+use crate::utils::conditions::{self, SheetRules, RuleOutcome};
+use crate::utils::styles::StyleCheck;
+use crate::utils::validation;
+use crate::utils::xlsx_parts::XlsxParts;
+
+struct Extraction {
+    function: String,
+    label: String,
+    instructions: Map<String, Value>,
+    rules: SheetRules,
+}
+
+impl Extraction {
+    // Name used when reporting rule events for this extraction
+    fn name(&self) -> &str {
+        if self.label.is_empty() { &self.function } else { &self.label }
+    }
+}
 
 fn extend_unique<T: PartialEq>(vec: &mut Vec<T>, value: T) {
     if !vec.contains(&value) {
@@ -10,114 +36,398 @@ fn extend_unique<T: PartialEq>(vec: &mut Vec<T>, value: T) {
     }
 }
 
-pub async fn process_file(file_path: String, extraction_details: Vec<Value>) -> Result<Value, Error> {
-    let mut results = Map::new();
-    results.insert("filepath".to_string(), Value::String(file_path.clone()));
+pub async fn process_file(file_path: String, extraction_details: Vec<Value>) -> Result<(Value, FileReport), Error> {
+    let workbook = match open_workbook_auto(&file_path) {
+        Ok(workbook) => workbook,
+        Err(err) => {
+            let base_filename = conversions::extract_filename(&file_path);
+            println!("Error: {} :: {}", base_filename, err);
+            return Ok((Value::Null, FileReport::failed(err.to_string()))); // or return an empty object, depending on your needs
+        }
+    };
+    let open_parts = || XlsxParts::read(BufReader::new(File::open(&file_path)?));
+    extract_workbook(workbook, &file_path, &extraction_details, open_parts)
+}
 
-    for extract in extraction_details.iter() {
-        let map = match extract {
-            Value::Object(map) => map,
-            _ => return Err(Error::msg("Extraction detail should be a JSON object")),
-        };
+pub async fn process_archive_member(archive_path: String, member: String, extraction_details: Vec<Value>) -> Result<(Value, FileReport), Error> {
+    let data = match archive::read_member(&archive_path, &member) {
+        Ok(data) => data,
+        Err(err) => {
+            println!("Error: {} :: {}", archive::member_key(&archive_path, &member), err);
+            return Ok((Value::Null, FileReport::failed(err.to_string())));
+        }
+    };
+    process_bytes(&format!("{}/{}", archive_path, member), &data, &extraction_details)
+}
+
+/// Processes a workbook that is already in memory, such as an archive member or a file read for its cache key.
+/// The format is picked from the extension of the path.
+pub fn process_bytes(file_path: &str, data: &[u8], extraction_details: &[Value]) -> Result<(Value, FileReport), Error> {
+    let workbook = match open_member_workbook(file_path, Cursor::new(data)) {
+        Ok(workbook) => workbook,
+        Err(err) => {
+            println!("Error: {} :: {}", file_path, err);
+            return Ok((Value::Null, FileReport::failed(err.to_string())));
+        }
+    };
+    let open_parts = || XlsxParts::read(Cursor::new(data));
+    extract_workbook(workbook, file_path, extraction_details, open_parts)
+}
 
-        let mut workbook = match open_workbook_auto(&file_path) {
-            Ok(workbook) => workbook,
-            Err(err) => {
-                let base_filename = conversions::extract_filename(&file_path);
-                println!("Error: {} :: {}", base_filename, err);
-                return Ok(Value::Null); // or return an empty object, depending on your needs
+// Picks the reader from the member extension, mirroring calamine's open_workbook_auto
+pub fn open_member_workbook<RS: Read + Seek + Clone>(member: &str, data: RS) -> Result<Sheets<RS>, Error> {
+    let extension = Path::new(member)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or_default()
+        .to_lowercase();
+    let workbook = match extension.as_str() {
+        "xls" | "xla" => Sheets::Xls(open_workbook_from_rs::<Xls<_>, _>(data)?),
+        "xlsx" | "xlsm" | "xlam" => Sheets::Xlsx(open_workbook_from_rs::<Xlsx<_>, _>(data)?),
+        "xlsb" => Sheets::Xlsb(open_workbook_from_rs::<Xlsb<_>, _>(data)?),
+        "ods" => Sheets::Ods(open_workbook_from_rs::<Ods<_>, _>(data)?),
+        // Like open_workbook_auto, other extensions are detected from the content
+        _ => open_workbook_auto_from_rs(data).map_err(|_| Error::msg(format!("Unsupported workbook format '{}'", member)))?,
+    };
+    Ok(workbook)
+}
+
+// Excel Tables are only available in xlsx files, and only loaded when a "table" extraction needs them
+// A table part that cannot be read is left out, and its extractions then report the table as not found
+fn load_tables<RS: Read + Seek>(workbook: &mut Sheets<RS>, extraction_details: &[Value], file_path: &str) -> Vec<Table<Data>> {
+    let uses_tables = extraction_details.iter()
+        .filter_map(|extract| extract.get("extractions").and_then(Value::as_array))
+        .flatten()
+        .any(|extraction| extraction.get("function").and_then(Value::as_str) == Some("table"));
+    let mut tables = Vec::new();
+    if let (true, Sheets::Xlsx(xlsx)) = (uses_tables, workbook) {
+        if let Err(e) = xlsx.load_tables() {
+            println!("{}: Tables could not be read: {}", conversions::extract_filename(file_path), e);
+            return tables;
+        }
+        let table_names: Vec<String> = xlsx.table_names().into_iter().cloned().collect();
+        for table_name in table_names {
+            match xlsx.table_by_name(&table_name) {
+                Ok(table) => tables.push(table),
+                Err(e) => println!("{}: Table '{}' could not be read: {}", conversions::extract_filename(file_path), table_name, e),
             }
-        };
+        }
+    }
+    tables
+}
 
-        let mut sheet_names: Vec<String> = Vec::new();
-        if let Some(sheets) = map.get("sheets") {
-            if let Some(sheets_array) = sheets.as_array() {
-                let skip_sheets = map.get("skip_sheets")
-                    .and_then(|v| v.as_array())
-                    .map(|arr| arr.iter().cloned().collect::<Vec<_>>())
-                    .unwrap_or_else(|| Vec::new());
-
-                for sheet in sheets_array {
-                    if let Some(sheet_str) = sheet.as_str() {
-                        if sheet_str.contains('*') {
-                            for sheet_name in match_sheet_names(&workbook.sheet_names().to_vec(), sheet_str) {
-                                if !skip_sheets.iter().any(|s| s == &sheet_name) {
-                                    extend_unique(&mut sheet_names, sheet_name);
-                                }
-                            }
-                        } else {
-                            let sheet_name = sheet_str.to_string();
+// True if any extraction uses one of the functions
+fn uses_function(extraction_details: &[Value], names: &[&str]) -> bool {
+    extraction_details.iter()
+        .filter_map(|extract| extract.get("extractions").and_then(Value::as_array))
+        .flatten()
+        .filter_map(|extraction| extraction.get("function").and_then(Value::as_str))
+        .any(|function| names.contains(&function))
+}
+
+// True if any extraction has one of the instructions
+fn uses_instruction(extraction_details: &[Value], names: &[&str]) -> bool {
+    extraction_details.iter()
+        .filter_map(|extract| extract.get("extractions").and_then(Value::as_array))
+        .flatten()
+        .filter_map(|extraction| extraction.get("instructions").and_then(Value::as_object))
+        .any(|instructions| names.iter().any(|name| instructions.contains_key(*name)))
+}
+
+// Whether a "skip_when" rule checks the style of a cell, which is read from the xml parts
+fn uses_style_rules(extraction_details: &[Value]) -> bool {
+    extraction_details.iter()
+        .filter_map(|extract| extract.get("extractions").and_then(Value::as_array))
+        .flatten()
+        .filter_map(|extraction| extraction.get("instructions").and_then(|instructions| instructions.get("skip_when")))
+        .flat_map(|rules| match rules {
+            Value::Array(rules) => rules.iter().collect(),
+            rule => vec![rule],
+        })
+        .filter_map(Value::as_object)
+        .any(|rule| matches!(StyleCheck::parse(rule), Ok(Some(_))))
+}
+
+// The xml parts are only read for xlsx files, when an extraction lists comments, controls or styled cells, includes
+// annotations with the cell values, checks data validations or skips rows by style
+fn load_parts<RS: Read + Seek>(
+    workbook: &Sheets<RS>,
+    extraction_details: &[Value],
+    open_parts: impl FnOnce() -> Result<XlsxParts, Error>,
+) -> Result<Option<XlsxParts>, Error> {
+    let uses_parts = uses_function(extraction_details, &["comments", "controls", "styled_cells"])
+        || uses_instruction(extraction_details, &["include", "validation"])
+        || uses_style_rules(extraction_details);
+    match (uses_parts, workbook) {
+        (true, Sheets::Xlsx(_)) => Ok(Some(open_parts()?)),
+        _ => Ok(None),
+    }
+}
+
+// Collects the sheets named in sheet-qualified references ("Cover!B3") or in defined names used by the instructions
+fn collect_sheet_references(value: &Value, defined_names: &[(String, String)], sheets: &mut Vec<String>) {
+    match value {
+        Value::String(s) => {
+            let reference = match defined_names.iter().find(|(name, _)| name.eq_ignore_ascii_case(s)) {
+                Some((_, formula)) => conversions::parse_reference(formula.trim_start_matches('=')),
+                None if s.contains('!') => conversions::parse_reference(s),
+                None => return,
+            };
+            if let Ok(Some(sheet)) = reference.map(|reference| reference.sheet) {
+                extend_unique(sheets, sheet);
+            }
+        },
+        Value::Array(arr) => arr.iter().for_each(|v| collect_sheet_references(v, defined_names, sheets)),
+        Value::Object(map) => map.values().for_each(|v| collect_sheet_references(v, defined_names, sheets)),
+        _ => (),
+    }
+}
+
+// Loads the referenced sheets once per workbook, matching their names case-insensitively like Excel;
+// missing sheets are reported when a reference is resolved.
+// Data validation lists also read their options from the sheets they refer to, and controls their linked cells.
+fn load_referenced_sheets<RS: Read + Seek>(
+    workbook: &mut Sheets<RS>,
+    extraction_details: &[Value],
+    defined_names: &[(String, String)],
+    parts: Option<&XlsxParts>,
+) -> Result<HashMap<String, Range<Data>>, Error> {
+    let mut names = Vec::new();
+    for extract in extraction_details {
+        collect_sheet_references(extract, defined_names, &mut names);
+    }
+    let sheet_names = workbook.sheet_names();
+    if let Some(parts) = parts {
+        // Only the extracted sheets, and the other sheets they read, are scanned
+        let mut extracted = names.clone();
+        for map in extraction_details.iter().filter_map(Value::as_object) {
+            for name in select_sheets(map, &sheet_names).unwrap_or_default() {
+                extend_unique(&mut extracted, name);
+            }
+        }
+        if uses_instruction(extraction_details, &["validation"]) {
+            for name in validation::source_sheets(parts, defined_names, &extracted) {
+                extend_unique(&mut names, name);
+            }
+        }
+        if uses_function(extraction_details, &["controls"]) {
+            for name in controls::linked_sheets(parts, &extracted) {
+                extend_unique(&mut names, name);
+            }
+        }
+    }
+    Ok(names.into_iter()
+        .filter_map(|name| sheet_names.iter().find(|sheet| sheet.eq_ignore_ascii_case(&name)).cloned())
+        .filter_map(|name| workbook.worksheet_range(&name).ok().map(|range| (name, range)))
+        .collect())
+}
+
+// The sheets an extraction block applies to: its "sheets" names and patterns, without its "skip_sheets"
+fn select_sheets(map: &Map<String, Value>, workbook_sheets: &[String]) -> Result<Vec<String>, Error> {
+    let mut sheet_names: Vec<String> = Vec::new();
+    if let Some(sheets) = map.get("sheets") {
+        if let Some(sheets_array) = sheets.as_array() {
+            let skip_sheets = map.get("skip_sheets")
+                .and_then(|v| v.as_array())
+                .map(|arr| arr.to_vec())
+                .unwrap_or_default();
+
+            for sheet in sheets_array {
+                if let Some(sheet_str) = sheet.as_str() {
+                    if sheet_str.contains('*') {
+                        for sheet_name in match_sheet_names(workbook_sheets, sheet_str) {
                             if !skip_sheets.iter().any(|s| s == &sheet_name) {
                                 extend_unique(&mut sheet_names, sheet_name);
                             }
                         }
                     } else {
-                        return Err(Error::msg("Invalid sheet name"));
+                        let sheet_name = sheet_str.to_string();
+                        if !skip_sheets.iter().any(|s| s == &sheet_name) {
+                            extend_unique(&mut sheet_names, sheet_name);
+                        }
                     }
+                } else {
+                    return Err(Error::msg("Invalid sheet name"));
                 }
-            } else {
-                return Err(Error::msg("Invalid \"sheets\" value in extraction details"));
             }
         } else {
-            return Err(Error::msg("Missing \"sheets\" key in extraction details"));
+            return Err(Error::msg("Invalid \"sheets\" value in extraction details"));
         }
+    } else {
+        return Err(Error::msg("Missing \"sheets\" key in extraction details"));
+    }
+    Ok(sheet_names)
+}
+
+fn parse_extractions(map: &Map<String, Value>) -> Result<Vec<Extraction>, Error> {
+    map.get("extractions")
+        .and_then(|extr| extr.as_array())
+        .ok_or_else(|| Error::msg("Missing or invalid \"extractions\" key in extraction details"))?
+        .iter()
+        .map(|extr| {
+            let obj = extr.as_object().ok_or_else(|| Error::msg("Each extraction should be a JSON object"))?;
+            let function = obj.get("function")
+                .and_then(|f| f.as_str())
+                .ok_or_else(|| Error::msg("Missing 'function' key"))?
+                .to_string();
+            let function_label = obj.get("label")
+                .and_then(|f| f.as_str())
+                .unwrap_or("") // Provide "" as default if 'label' is missing
+                .to_string();
+            let instructions = obj.get("instructions")
+                .and_then(|i| i.as_object())
+                .cloned()
+                .ok_or_else(|| Error::msg("Missing 'instructions' key"))?;
+            let rules = SheetRules::parse(obj)?;
+            Ok(Extraction { function, label: function_label, instructions, rules })
+        })
+        .collect()
+}
 
-        let break_if_null = map.get("break_if_null").and_then(|f| f.as_str());
-        let extractions = map
-            .get("extractions")
-            .and_then(|extr| extr.as_array())
-            .ok_or_else(|| Error::msg("Missing or invalid \"extractions\" key in extraction details"))?
-            .iter()
-            .map(|extr| {
-                let obj = extr.as_object().ok_or_else(|| Error::msg("Each extraction should be a JSON object"))?;
-                let function = obj.get("function")
-                    .and_then(|f| f.as_str())
-                    .ok_or_else(|| Error::msg("Missing 'function' key"))?
-                    .to_string();
-                let function_label = obj.get("label")
-                    .and_then(|f| f.as_str())
-                    .unwrap_or("") // Provide "" as default if 'label' is missing
-                    .to_string();
-                let instructions = obj.get("instructions")
-                    .and_then(|i| i.as_object())
-                    .cloned()
-                    .ok_or_else(|| Error::msg("Missing 'instructions' key"))?;
-                Ok((function, function_label, instructions))
-            })
-            .collect::<Result<Vec<(String, String, Map<String, Value>)>, Error>>()?;
+/// Checks the structure of the extraction details (sheets, rules, "when" conditions and extractions) once per run,
+/// so that a broken spec is reported as an error instead of as a failure of every file.
+pub fn validate_details(extraction_details: &[Value]) -> Result<(), Error> {
+    for extract in extraction_details {
+        let map = extract.as_object().ok_or_else(|| Error::msg("Extraction detail should be a JSON object"))?;
+        select_sheets(map, &[])?;
+        SheetRules::parse(map)?;
+        if let Some(value) = map.get("when") {
+            conditions::parse_conditions(value)?;
+        }
+        parse_extractions(map)?;
+    }
+    Ok(())
+}
+
+// Moves the warnings raised on a sheet into the file report, under the extraction (or rules) that raised them
+fn take_warnings(report: &mut FileReport, context: &ExtractionContext, sheet_name: &str, extraction: &str) {
+    for warning in context.warnings.take() {
+        report.add_warning(sheet_name, extraction, &warning);
+    }
+}
+
+fn extract_workbook<RS: Read + Seek>(
+    mut workbook: Sheets<RS>,
+    file_path: &str,
+    extraction_details: &[Value],
+    open_parts: impl FnOnce() -> Result<XlsxParts, Error>,
+) -> Result<(Value, FileReport), Error> {
+    let mut report = FileReport::default();
+    let defined_names = workbook.defined_names().to_vec();
+    let tables = load_tables(&mut workbook, extraction_details, file_path);
+    let parts = load_parts(&workbook, extraction_details, open_parts)?;
+    let referenced_sheets = load_referenced_sheets(&mut workbook, extraction_details, &defined_names, parts.as_ref())?;
+    let mut results = Map::new();
+    results.insert("filepath".to_string(), Value::String(file_path.to_string()));
+    // Sheets checked by the "when" conditions of variant blocks, and those that matched one of them
+    let mut variant_sheets: Vec<String> = Vec::new();
+    let mut matched_sheets: Vec<String> = Vec::new();
+
+    for extract in extraction_details.iter() {
+        let map = match extract {
+            Value::Object(map) => map,
+            _ => return Err(Error::msg("Extraction detail should be a JSON object")),
+        };
+
+        let sheet_names = select_sheets(map, workbook.sheet_names().as_slice())?;
+
+        let block_rules = SheetRules::parse(map)?;
+        // A block with a "when" condition only applies to sheets where every condition is met
+        let when = match map.get("when") {
+            Some(value) => conditions::parse_conditions(value)?,
+            None => Vec::new(),
+        };
+        let variant = map.get("variant").and_then(Value::as_str);
+        let extractions = parse_extractions(map)?;
+        // Extractions halted by their own stop rule are not applied to the remaining sheets
+        let mut stopped = vec![false; extractions.len()];
         for sheet_name in &sheet_names {
             let sheet = match workbook.worksheet_range(sheet_name) {
                 Ok(sheet) => sheet,
                 Err(_) => {
-                    let base_filename = conversions::extract_filename(&file_path);
+                    let base_filename = conversions::extract_filename(file_path);
                     println!("{}: Sheet '{}' not found, skipping extraction.", base_filename, sheet_name);
+                    extend_unique(&mut report.skipped_sheets, sheet_name.clone());
                     continue;
                 }
             };
 
-            if let Some(break_if_null_value) = break_if_null {
-                let (row, col) = conversions::address_to_row_col(break_if_null_value)?;
-                let (cell_value, _) = manipulations::extract_cell_value(&sheet, row, col, false)?;
-                if let Some(value) = cell_value {
-                    if value.is_null() {
-                        break; // Break out of the sheet loop
-                    }
-                } else {
+            let context = ExtractionContext { sheet_name, defined_names: &defined_names, tables: &tables, sheets: &referenced_sheets, parts: parts.as_ref(), warnings: RefCell::new(Vec::new()) };
+            // The rules of a variant block only apply to the sheets it matches
+            let mut when_met = true;
+            for condition in &when {
+                if !condition.is_met(&sheet, &context)? {
+                    when_met = false;
                     break;
                 }
             }
+            take_warnings(&mut report, &context, sheet_name, "when");
+            if !when.is_empty() {
+                extend_unique(&mut variant_sheets, sheet_name.clone());
+                if when_met {
+                    extend_unique(&mut matched_sheets, sheet_name.clone());
+                }
+            }
+            if !when_met {
+                continue;
+            }
+
+            let outcome = block_rules.evaluate(&sheet, &context)?;
+            take_warnings(&mut report, &context, sheet_name, "rules");
+            match outcome {
+                RuleOutcome::Extract => (),
+                RuleOutcome::Skip => {
+                    report.add_rule_event(sheet_name, None, "skip");
+                    continue;
+                },
+                RuleOutcome::Stop => {
+                    report.add_rule_event(sheet_name, None, "stop");
+                    break; // Break out of the sheet loop
+                },
+            }
 
             let mut sheet_results = Map::new();
-            for (function, label, instructions) in &extractions {
+            if let Some(variant) = variant {
+                sheet_results.insert("_variant".to_string(), Value::String(variant.to_string()));
+            }
+            for (index, extraction) in extractions.iter().enumerate() {
+                if stopped[index] {
+                    continue;
+                }
+                let outcome = extraction.rules.evaluate(&sheet, &context)?;
+                take_warnings(&mut report, &context, sheet_name, extraction.name());
+                match outcome {
+                    RuleOutcome::Extract => (),
+                    RuleOutcome::Skip => {
+                        report.add_rule_event(sheet_name, Some(extraction.name()), "skip");
+                        continue;
+                    },
+                    RuleOutcome::Stop => {
+                        report.add_rule_event(sheet_name, Some(extraction.name()), "stop");
+                        stopped[index] = true;
+                        continue;
+                    },
+                }
+                let (function, label, instructions) = (&extraction.function, &extraction.label, &extraction.instructions);
                 let cells_object = match function.as_str() {
-                    "single_cells" => single_cells::extract_values(&sheet, &instructions),
-                    "multirow_patterns" => multirow_patterns::extract_rows(&sheet, &instructions),
-                    "dataframe" => dataframe::extract_dataframe(&sheet, &instructions),
+                    "single_cells" => single_cells::extract_values(&sheet, instructions, &context),
+                    "multirow_patterns" => multirow_patterns::extract_rows(&sheet, instructions, &context),
+                    "dataframe" => dataframe::extract_dataframe(&sheet, instructions, &context),
+                    "key_value" => key_value::extract_pairs(&sheet, instructions, &context),
+                    "detect_tables" => table_detection::detect_tables(&sheet, instructions, &context),
+                    "table" => excel_tables::extract_table(&context, instructions),
+                    "matrix" => matrix::extract_matrix(&sheet, instructions, &context),
+                    "repeating_blocks" => repeating_blocks::extract_blocks(&sheet, instructions, &context),
+                    "comments" => annotations::extract_comments(&sheet, instructions, &context),
+                    "controls" => controls::extract_controls(&sheet, instructions, &context),
+                    "styled_cells" => styles::extract_styled_cells(&sheet, instructions, &context),
                     _ => {
                         println!("Unsupported function type '{}'", function);
+                        extend_unique(&mut report.unsupported_functions, function.clone());
                         continue;
                     }
                 }?;
+                take_warnings(&mut report, &context, sheet_name, extraction.name());
 
                 if label.is_empty() {
                     for (key, value) in cells_object {
@@ -150,6 +460,12 @@ pub async fn process_file(file_path: String, extraction_details: Vec<Value>) ->
             }
         }
     }
+    for sheet_name in variant_sheets {
+        if !matched_sheets.contains(&sheet_name) {
+            println!("{}: Sheet '{}' matches no variant.", conversions::extract_filename(file_path), sheet_name);
+            report.unmatched_sheets.push(sheet_name);
+        }
+    }
 
-    Ok(Value::Object(results))
+    Ok((Value::Object(results), report))
 }
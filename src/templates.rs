@@ -0,0 +1,263 @@
+use calamine::{Reader, Sheets, Data, open_workbook_auto};
+use serde_json::{Map, Value};
+use anyhow::{Result, Error};
+use std::collections::{HashMap, HashSet};
+use std::io::{Cursor, Read, Seek};
+use crate::archive;
+use crate::read_excel::open_member_workbook;
+use crate::utils::conversions;
+
+/// Structure of a single sheet: its name, used range and static text labels.
+struct SheetFingerprint {
+    name: String,
+    dimensions: Option<(u32, u32)>,
+    labels: Vec<(String, String)>,
+}
+
+/// Structure of a workbook, used to group files that share a template.
+pub struct Fingerprint {
+    sheets: Vec<SheetFingerprint>,
+}
+
+impl Fingerprint {
+    // Sheet names and label cells make up the structure; dimensions are left out since they grow with the data
+    fn features(&self) -> HashSet<String> {
+        let mut features = HashSet::new();
+        for sheet in &self.sheets {
+            features.insert(format!("sheet:{}", sheet.name));
+            for (address, text) in &sheet.labels {
+                features.insert(format!("label:{}!{}={}", sheet.name, address, text));
+            }
+        }
+        features
+    }
+
+    fn sheet_names(&self) -> Value {
+        Value::Array(self.sheets.iter().map(|sheet| Value::String(sheet.name.clone())).collect())
+    }
+
+    fn to_value(&self) -> Value {
+        let mut sheets = Map::new();
+        for sheet in &self.sheets {
+            let mut map = Map::new();
+            let dimensions = match sheet.dimensions {
+                Some((rows, cols)) => Value::Array(vec![Value::from(rows), Value::from(cols)]),
+                None => Value::Null,
+            };
+            map.insert("dimensions".to_string(), dimensions);
+            map.insert("labels".to_string(), Value::from(sheet.labels.len()));
+            sheets.insert(sheet.name.clone(), Value::Object(map));
+        }
+        Value::Object(sheets)
+    }
+}
+
+fn fingerprint_workbook<RS: Read + Seek>(mut workbook: Sheets<RS>) -> Result<Fingerprint, Error> {
+    let mut sheets = Vec::new();
+    for name in workbook.sheet_names() {
+        let range = workbook.worksheet_range(&name)?;
+        let dimensions = range.end().map(|(row, col)| (row + 1, col + 1));
+        let mut labels = Vec::new();
+        for (row, col, cell) in range.used_cells() {
+            if let Data::String(text) = cell {
+                let text = text.trim();
+                if !text.is_empty() {
+                    let (start_row, start_col) = range.start().unwrap_or((0, 0));
+                    let address = format!("{}{}", conversions::column_index_to_name(start_col + col as u32), start_row + row as u32 + 1);
+                    labels.push((address, text.to_string()));
+                }
+            }
+        }
+        sheets.push(SheetFingerprint { name, dimensions, labels });
+    }
+    Ok(Fingerprint { sheets })
+}
+
+pub async fn fingerprint_file(file_path: String) -> Option<Fingerprint> {
+    let result = open_workbook_auto(&file_path)
+        .map_err(Error::from)
+        .and_then(fingerprint_workbook);
+    match result {
+        Ok(fingerprint) => Some(fingerprint),
+        Err(err) => {
+            println!("Error: {} :: {}", conversions::extract_filename(&file_path), err);
+            None
+        }
+    }
+}
+
+pub async fn fingerprint_archive_member(archive_path: String, member: String) -> Option<Fingerprint> {
+    let result = archive::read_member(&archive_path, &member)
+        .and_then(|data| open_member_workbook(&member, Cursor::new(data)))
+        .and_then(fingerprint_workbook);
+    match result {
+        Ok(fingerprint) => Some(fingerprint),
+        Err(err) => {
+            println!("Error: {} :: {}", archive::member_key(&archive_path, &member), err);
+            None
+        }
+    }
+}
+
+// Share of the template's sheets and labels found in the file, so that the answers typed into a file don't lower it
+fn containment(template: &HashSet<String>, file: &HashSet<String>) -> f64 {
+    if template.is_empty() {
+        return 1.0;
+    }
+    template.intersection(file).count() as f64 / template.len() as f64
+}
+
+// Jaccard similarity of two feature sets
+fn jaccard(a: &HashSet<String>, b: &HashSet<String>) -> f64 {
+    let union = a.union(b).count();
+    if union == 0 {
+        return 1.0;
+    }
+    a.intersection(b).count() as f64 / union as f64
+}
+
+// Returns the index and similarity of the most similar feature set, if any reaches the threshold.
+// Ties (e.g. a file fully containing two nested templates) go to the closest set by Jaccard similarity,
+// then to the larger set, then to the first one, so the result does not depend on the order of the candidates.
+fn best_match(
+    features: &HashSet<String>,
+    candidates: &[HashSet<String>],
+    threshold: f64,
+    similarity: fn(&HashSet<String>, &HashSet<String>) -> f64,
+) -> Option<(usize, f64)> {
+    candidates.iter()
+        .enumerate()
+        .map(|(index, candidate)| (index, similarity(candidate, features)))
+        .filter(|(_, score)| *score >= threshold)
+        .max_by(|a, b| a.1.total_cmp(&b.1)
+            .then_with(|| jaccard(&candidates[a.0], features).total_cmp(&jaccard(&candidates[b.0], features)))
+            .then_with(|| candidates[a.0].len().cmp(&candidates[b.0].len()))
+            .then_with(|| b.0.cmp(&a.0)))
+}
+
+/// Clusters files by structure and matches them against known template reference files.
+/// Files that neither match a known template nor share a cluster with another file are flagged as outliers.
+pub fn detect_templates(fingerprints: Vec<(String, Option<Fingerprint>)>, templates: Vec<(String, Fingerprint)>, threshold: f64) -> Value {
+    let template_features: Vec<HashSet<String>> = templates.iter().map(|(_, fingerprint)| fingerprint.features()).collect();
+    // Labels found in a single file are taken as answers rather than template text, and left out of the clustering
+    let file_features: Vec<Option<HashSet<String>>> = fingerprints.iter()
+        .map(|(_, fingerprint)| fingerprint.as_ref().map(Fingerprint::features))
+        .collect();
+    let mut file_counts: HashMap<&str, usize> = HashMap::new();
+    for feature in file_features.iter().flatten().flatten() {
+        *file_counts.entry(feature).or_default() += 1;
+    }
+    let static_features: Vec<Option<HashSet<String>>> = file_features.iter()
+        .map(|features| features.as_ref().map(|features| features.iter()
+            .filter(|feature| feature.starts_with("sheet:")
+                || file_counts[feature.as_str()] > 1
+                || template_features.iter().any(|template| template.contains(*feature)))
+            .cloned()
+            .collect()))
+        .collect();
+    let mut cluster_features: Vec<HashSet<String>> = Vec::new();
+    let mut clusters: Vec<Vec<String>> = Vec::new();
+    let mut cluster_sheets: Vec<Value> = Vec::new();
+    let mut files = Map::new();
+    let mut unreadable = Vec::new();
+
+    for (((base_key, fingerprint), features), static_features) in fingerprints.into_iter().zip(file_features).zip(static_features) {
+        let mut key = base_key.clone();
+        let mut counter = 1;
+        // Ensure the key is unique by appending a counter if needed, like the extraction results
+        while files.contains_key(&key) || unreadable.contains(&Value::String(key.clone())) {
+            key = format!("{}_{}", base_key, counter);
+            counter += 1;
+        }
+        let (Some(fingerprint), Some(features), Some(static_features)) = (fingerprint, features, static_features) else {
+            unreadable.push(Value::String(key));
+            continue;
+        };
+
+        // The first file of each cluster is its representative. A file that shares no label with another file
+        // or a template starts its own cluster.
+        let has_labels = static_features.iter().any(|feature| feature.starts_with("label:"));
+        let cluster = match best_match(&static_features, &cluster_features, threshold, jaccard) {
+            Some((index, _)) if has_labels => index,
+            _ => {
+                cluster_features.push(if has_labels { static_features } else { HashSet::new() });
+                clusters.push(Vec::new());
+                cluster_sheets.push(fingerprint.sheet_names());
+                clusters.len() - 1
+            }
+        };
+        clusters[cluster].push(key.clone());
+
+        let mut file = Map::new();
+        match best_match(&features, &template_features, threshold, containment) {
+            Some((index, score)) => {
+                file.insert("template".to_string(), Value::String(templates[index].0.clone()));
+                file.insert("similarity".to_string(), Value::from(score));
+            },
+            None => {
+                file.insert("template".to_string(), Value::Null);
+                file.insert("similarity".to_string(), Value::Null);
+            },
+        }
+        file.insert("cluster".to_string(), Value::from(cluster));
+        file.insert("sheets".to_string(), fingerprint.to_value());
+        files.insert(key, Value::Object(file));
+    }
+
+    let mut outliers = Vec::new();
+    for (key, file) in &files {
+        let cluster = file.get("cluster").and_then(Value::as_u64).unwrap_or_default() as usize;
+        if file.get("template").is_none_or(Value::is_null) && clusters[cluster].len() < 2 {
+            outliers.push(Value::String(key.clone()));
+        }
+    }
+
+    let clusters: Vec<Value> = clusters.into_iter()
+        .zip(cluster_sheets)
+        .enumerate()
+        .map(|(index, (members, sheets))| {
+            let mut map = Map::new();
+            map.insert("id".to_string(), Value::from(index));
+            map.insert("sheets".to_string(), sheets);
+            map.insert("files".to_string(), Value::Array(members.into_iter().map(Value::String).collect()));
+            Value::Object(map)
+        })
+        .collect();
+
+    let mut result = Map::new();
+    result.insert("files".to_string(), Value::Object(files));
+    result.insert("clusters".to_string(), Value::Array(clusters));
+    result.insert("outliers".to_string(), Value::Array(outliers));
+    result.insert("unreadable".to_string(), Value::Array(unreadable));
+    Value::Object(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(features: &[&str]) -> HashSet<String> {
+        features.iter().map(|feature| feature.to_string()).collect()
+    }
+
+    #[test]
+    fn best_match_prefers_the_closest_of_nested_templates() {
+        let small = set(&["sheet:Form", "label:Name"]);
+        let large = set(&["sheet:Form", "label:Name", "label:Region", "label:Budget"]);
+        let full = set(&["sheet:Form", "label:Name", "label:Region", "label:Budget", "label:Alice"]);
+        let short = set(&["sheet:Form", "label:Name", "label:Alice"]);
+        // The full file contains both templates, whatever their order
+        assert_eq!(best_match(&full, &[small.clone(), large.clone()], 0.8, containment), Some((1, 1.0)));
+        assert_eq!(best_match(&full, &[large.clone(), small.clone()], 0.8, containment), Some((0, 1.0)));
+        // The short file only contains the small template
+        assert_eq!(best_match(&short, &[large.clone(), small.clone()], 0.8, containment), Some((1, 1.0)));
+        assert_eq!(best_match(&short, &[large], 0.8, containment), None);
+    }
+
+    #[test]
+    fn best_match_keeps_the_first_of_equal_candidates() {
+        let template = set(&["sheet:Form", "label:Name"]);
+        let file = set(&["sheet:Form", "label:Name"]);
+        assert_eq!(best_match(&file, &[template.clone(), template], 0.8, containment), Some((0, 1.0)));
+    }
+}
@@ -6,8 +6,22 @@ use serde_json::{Value, Map};
 pub mod single_cells;
 pub mod multirow_patterns;
 pub mod dataframe;
+pub mod key_value;
+pub mod table_detection;
 pub mod conversions;
 pub mod manipulations;
+pub mod conditions;
+pub mod context;
+pub mod excel_tables;
+pub mod matrix;
+pub mod reader;
+pub mod text;
+pub mod repeating_blocks;
+pub mod xlsx_parts;
+pub mod annotations;
+pub mod validation;
+pub mod controls;
+pub mod styles;
 
 fn pydict_to_json_value(pydict: &Bound<'_, PyDict>) -> PyResult<Value> {
     let mut map = Map::new(); // Use serde_json::Map directly
@@ -0,0 +1,347 @@
+use anyhow::{Result, Error};
+use calamine::{Range, Data};
+use serde_json::{Map, Value};
+use indexmap::IndexMap;
+use std::collections::HashMap;
+use crate::utils::conversions;
+use crate::utils::context::ExtractionContext;
+use crate::utils::reader::CellReader;
+use crate::utils::styles::SheetStyles;
+use crate::utils::validation::Validations;
+use crate::utils::xlsx_parts::{self, cell_position, flag, Element, XlsxParts};
+
+/// Which annotations are read along with the cell values, from the "include" instruction,
+/// e.g. ["comment", "hyperlink"] or "style", and the "validation" instruction.
+#[derive(Default, Clone)]
+pub struct Include {
+    pub comment: bool,
+    pub hyperlink: bool,
+    pub rich_text: bool,
+    pub style: bool,
+    pub validation: bool,
+    // Codes of the data validation options, e.g. {"Yes": 1, "No": 0}
+    lookup: Option<Map<String, Value>>,
+}
+
+impl Include {
+    /// None when the instructions neither include annotations nor check data validations.
+    pub fn parse(instructions: &Map<String, Value>) -> Result<Option<Self>, Error> {
+        let mut include = Include::default();
+        match instructions.get("validation") {
+            None | Some(Value::Bool(false)) => (),
+            Some(Value::Bool(true)) => include.validation = true,
+            Some(Value::Object(options)) => {
+                include.validation = true;
+                include.lookup = match options.get("lookup") {
+                    Some(Value::Object(lookup)) => Some(lookup.clone()),
+                    None => None,
+                    Some(_) => return Err(Error::msg("Invalid 'lookup' in 'validation'")),
+                };
+            },
+            Some(other) => return Err(Error::msg(format!("Invalid 'validation' option {}", other))),
+        }
+        let names = match instructions.get("include") {
+            None if include.validation => Vec::new(),
+            None => return Ok(None),
+            Some(Value::String(name)) => vec![name.as_str()],
+            Some(Value::Array(names)) => names.iter()
+                .map(|name| name.as_str().ok_or_else(|| Error::msg("Invalid 'include' option")))
+                .collect::<Result<_, Error>>()?,
+            Some(other) => return Err(Error::msg(format!("Invalid 'include' option {}", other))),
+        };
+        for name in names {
+            match name {
+                "comment" => include.comment = true,
+                "hyperlink" => include.hyperlink = true,
+                "rich_text" => include.rich_text = true,
+                "style" => include.style = true,
+                _ => return Err(Error::msg(format!("Invalid 'include' option '{}'", name))),
+            }
+        }
+        Ok(Some(include))
+    }
+}
+
+/// A cell comment (note), or the first comment of a thread with its replies.
+pub struct Comment {
+    pub author: Option<String>,
+    pub text: String,
+    pub replies: Vec<Comment>,
+}
+
+impl Comment {
+    pub fn to_value(&self) -> Value {
+        let mut map = Map::new();
+        map.insert("author".to_string(), self.author.clone().map_or(Value::Null, Value::String));
+        map.insert("text".to_string(), Value::String(self.text.clone()));
+        if !self.replies.is_empty() {
+            map.insert("replies".to_string(), Value::Array(self.replies.iter().map(Comment::to_value).collect()));
+        }
+        Value::Object(map)
+    }
+}
+
+// The text of a string item: a plain <t> or rich text <r> runs. Phonetic runs are skipped.
+fn item_text(item: &Element) -> String {
+    item.children.iter()
+        .map(|child| match child.name.as_str() {
+            "t" => child.text.clone(),
+            "r" => child.child("t").map(|t| t.text.clone()).unwrap_or_default(),
+            _ => String::new(),
+        })
+        .collect()
+}
+
+// The runs of a rich text string item, with the formatting set on each run, or None for plain text
+fn item_runs(item: &Element) -> Option<Vec<Value>> {
+    let runs: Vec<Value> = item.children("r")
+        .map(|run| {
+            let mut map = Map::new();
+            map.insert("text".to_string(), Value::String(run.child("t").map(|t| t.text.clone()).unwrap_or_default()));
+            for property in run.child("rPr").map(|properties| properties.children.as_slice()).unwrap_or_default() {
+                match property.name.as_str() {
+                    "b" => { map.insert("bold".to_string(), Value::Bool(flag(property))); },
+                    "i" => { map.insert("italic".to_string(), Value::Bool(flag(property))); },
+                    "strike" => { map.insert("strikethrough".to_string(), Value::Bool(flag(property))); },
+                    "u" => { map.insert("underline".to_string(), Value::String(property.attr("val").unwrap_or("single").to_string())); },
+                    "color" => if let Some(rgb) = property.attr("rgb") {
+                        map.insert("color".to_string(), Value::String(rgb.to_string()));
+                    },
+                    "sz" => if let Some(size) = property.attr("val").and_then(|size| size.parse::<f64>().ok()) {
+                        map.insert("size".to_string(), Value::from(size));
+                    },
+                    "rFont" => if let Some(font) = property.attr("val") {
+                        map.insert("font".to_string(), Value::String(font.to_string()));
+                    },
+                    _ => (),
+                }
+            }
+            Value::Object(map)
+        })
+        .collect();
+    if runs.is_empty() { None } else { Some(runs) }
+}
+
+/// Comments, hyperlinks, rich text, data validations and cell styles of a sheet, keyed by (1-based row, 0-based column) like the cell values.
+#[derive(Default)]
+pub struct SheetAnnotations {
+    pub comments: IndexMap<(u32, u32), Comment>,
+    hyperlinks: HashMap<(u32, u32), String>,
+    rich_text: HashMap<(u32, u32), Vec<Value>>,
+    styles: Option<SheetStyles>,
+    pub validations: Validations,
+}
+
+impl SheetAnnotations {
+    /// Reads the comments, hyperlinks, rich text and cell styles of a sheet from the xlsx parts. The cells of the worksheet
+    /// are only parsed for rich text and styles. Data validations are loaded separately, since they may read other sheets.
+    pub fn load(parts: &XlsxParts, sheet_name: &str, include: &Include) -> Result<Self, Error> {
+        let sheet_part = parts.sheet_part(sheet_name)?;
+        let mut annotations = SheetAnnotations::default();
+        if include.comment {
+            annotations.load_comments(parts, sheet_part)?;
+        }
+        if include.hyperlink || include.rich_text {
+            let worksheet = parts.worksheet(sheet_name, include.rich_text)?;
+            if include.hyperlink {
+                annotations.load_hyperlinks(parts, sheet_part, &worksheet)?;
+            }
+            if include.rich_text {
+                annotations.load_rich_text(parts, &worksheet)?;
+            }
+        }
+        if include.style {
+            annotations.styles = Some(SheetStyles::load(parts, sheet_name)?);
+        }
+        Ok(annotations)
+    }
+
+    fn load_comments(&mut self, parts: &XlsxParts, sheet_part: &str) -> Result<(), Error> {
+        for path in parts.related(sheet_part, "comments")? {
+            let comments = parts.xml(&path)?;
+            let authors: Vec<String> = comments.child("authors")
+                .map(|authors| authors.children("author").map(|author| author.text.clone()).collect())
+                .unwrap_or_default();
+            for comment in comments.child("commentList").map(|list| list.children.as_slice()).unwrap_or_default() {
+                if let Some(address) = comment.attr("ref") {
+                    let author = comment.attr("authorId")
+                        .and_then(|id| id.parse::<usize>().ok())
+                        .and_then(|id| authors.get(id).cloned());
+                    let text = comment.child("text").map(item_text).unwrap_or_default();
+                    self.comments.insert(cell_position(address)?, Comment { author, text, replies: Vec::new() });
+                }
+            }
+        }
+
+        // Threaded comments of recent Excel versions also have a placeholder note, which they replace
+        let persons: HashMap<String, String> = match parts.workbook_part("person")? {
+            Some(path) => parts.xml(&path)?
+                .children("person")
+                .filter_map(|person| Some((person.attr("id")?.to_string(), person.attr("displayName")?.to_string())))
+                .collect(),
+            None => HashMap::new(),
+        };
+        for path in parts.related(sheet_part, "threadedComment")? {
+            let threads = parts.xml(&path)?;
+            let mut thread_ids: HashMap<String, (u32, u32)> = HashMap::new();
+            for comment in threads.children("threadedComment") {
+                let (Some(address), Some(id)) = (comment.attr("ref"), comment.attr("id")) else { continue };
+                let position = cell_position(address)?;
+                let author = comment.attr("personId").and_then(|person| persons.get(person).cloned());
+                let text = comment.child("text").map(|text| text.text.clone()).unwrap_or_default();
+                let reply = Comment { author, text, replies: Vec::new() };
+                match comment.attr("parentId").and_then(|parent| thread_ids.get(parent)) {
+                    Some(thread) => {
+                        if let Some(first) = self.comments.get_mut(thread) {
+                            first.replies.push(reply);
+                        }
+                    },
+                    None => {
+                        thread_ids.insert(id.to_string(), position);
+                        self.comments.insert(position, reply);
+                    },
+                }
+            }
+        }
+        self.comments.sort_keys();
+        Ok(())
+    }
+
+    fn load_hyperlinks(&mut self, parts: &XlsxParts, sheet_part: &str, worksheet: &Element) -> Result<(), Error> {
+        let targets: HashMap<String, String> = parts.relationships(sheet_part)?
+            .into_iter()
+            .filter(|relationship| relationship.kind == "hyperlink")
+            .map(|relationship| (relationship.id, relationship.target))
+            .collect();
+        for hyperlink in worksheet.child("hyperlinks").map(|links| links.children.as_slice()).unwrap_or_default() {
+            let Some(address) = hyperlink.attr("ref") else { continue };
+            // Links to a place in the workbook only have a location, such as "Sheet2!A1"
+            let target = match (hyperlink.attr("id").and_then(|id| targets.get(id)), hyperlink.attr("location")) {
+                (Some(url), Some(location)) => format!("{}#{}", url, location),
+                (Some(url), None) => url.clone(),
+                (None, Some(location)) => format!("#{}", location),
+                (None, None) => continue,
+            };
+            // A hyperlink may cover a range of cells
+            let reference = conversions::parse_reference(address)?;
+            if let (Some(start_row), Some(end_row), Some(start_col), Some(end_col)) =
+                (reference.start_row, reference.end_row, reference.start_col, reference.end_col)
+            {
+                for row in start_row..=end_row {
+                    for col in start_col..=end_col {
+                        self.hyperlinks.insert((row, col), target.clone());
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn load_rich_text(&mut self, parts: &XlsxParts, worksheet: &Element) -> Result<(), Error> {
+        let shared_strings: Vec<Option<Vec<Value>>> = match parts.workbook_part("sharedStrings")? {
+            Some(path) => parts.xml(&path)?.children("si").map(item_runs).collect(),
+            None => Vec::new(),
+        };
+        for (position, cell) in xlsx_parts::sheet_cells(worksheet)? {
+            let runs = match cell.attr("t") {
+                Some("s") => cell.child("v")
+                    .and_then(|v| v.text.trim().parse::<usize>().ok())
+                    .and_then(|index| shared_strings.get(index).cloned().flatten()),
+                Some("inlineStr") => cell.child("is").and_then(item_runs),
+                _ => None,
+            };
+            if let Some(runs) = runs {
+                self.rich_text.insert(position, runs);
+            }
+        }
+        Ok(())
+    }
+
+    fn has_any(&self, position: &(u32, u32), include: &Include) -> bool {
+        (include.comment && self.comments.contains_key(position))
+            || (include.hyperlink && self.hyperlinks.contains_key(position))
+            || (include.rich_text && self.rich_text.contains_key(position))
+            || (include.style && self.styles.as_ref().is_some_and(|styles| styles.is_styled(*position)))
+    }
+
+    /// Wraps a cell value as {"value", "comment", "hyperlink", "rich_text", "style", "validation"}, with the included annotations
+    /// (null when missing). Values that are not one of the options of their data validation list are reported as warnings.
+    /// None when the value was dropped by the null policy and the cell has no annotation and no invalid entry either.
+    pub fn annotate(&self, value: Option<Value>, position: (u32, u32), include: &Include, context: &ExtractionContext) -> Option<Value> {
+        let validation = match include.validation {
+            true => self.validations.check(value.as_ref().unwrap_or(&Value::Null), position, include.lookup.as_ref()),
+            false => None,
+        };
+        let invalid = validation.as_ref().is_some_and(|validation| validation["valid"] == Value::Bool(false));
+        if invalid {
+            let address = format!("{}{}", conversions::column_index_to_name(position.1), position.0);
+            match value.as_ref().filter(|value| !value.is_null()) {
+                Some(value) => context.warn(format!("Value {} in {} is not one of the allowed values", value, address)),
+                None => context.warn(format!("Empty cell {} is not allowed by its data validation", address)),
+            }
+        }
+        if value.is_none() && !invalid && !self.has_any(&position, include) {
+            return None;
+        }
+        let mut map = Map::new();
+        map.insert("value".to_string(), value.unwrap_or(Value::Null));
+        if include.comment {
+            map.insert("comment".to_string(), self.comments.get(&position).map_or(Value::Null, Comment::to_value));
+        }
+        if include.hyperlink {
+            map.insert("hyperlink".to_string(), self.hyperlinks.get(&position).cloned().map_or(Value::Null, Value::String));
+        }
+        if include.rich_text {
+            map.insert("rich_text".to_string(), self.rich_text.get(&position).cloned().map_or(Value::Null, Value::Array));
+        }
+        if include.style {
+            map.insert("style".to_string(), self.styles.as_ref().map_or(Value::Null, |styles| styles.style(position).to_value()));
+        }
+        if include.validation {
+            map.insert("validation".to_string(), validation.unwrap_or(Value::Null));
+        }
+        Some(Value::Object(map))
+    }
+}
+
+/// Lists every comment of the sheet with its address, author, text and the value of the cell,
+/// optionally limited to a "range".
+pub fn extract_comments(sheet: &Range<Data>, instructions: &Map<String, Value>, context: &ExtractionContext) -> Result<IndexMap<String, Value>, Error> {
+    let bounds = match instructions.get("range") {
+        Some(range) => {
+            let range = range.as_str().ok_or_else(|| Error::msg("Invalid 'range'"))?;
+            let reference = context.resolve_reference(range)?;
+            if reference.sheet.as_deref().is_some_and(|name| name != context.sheet_name) {
+                return Err(Error::msg(format!("'range' should refer to sheet '{}'", context.sheet_name)));
+            }
+            Some(reference.bounds(sheet))
+        },
+        None => None,
+    };
+    let reader = CellReader::parse(instructions)?;
+    let include = Include { comment: true, ..Include::default() };
+    if context.xlsx_parts("Comments").is_none() {
+        return Ok(IndexMap::new());
+    }
+    let annotations = context.annotations(None, &include)?;
+
+    let mut records = Vec::new();
+    for ((row, col), comment) in &annotations.comments {
+        if let Some((start_row, end_row, start_col, end_col)) = bounds {
+            if !(start_row..=end_row).contains(row) || !(start_col..=end_col).contains(col) {
+                continue;
+            }
+        }
+        let mut record = Map::new();
+        record.insert("address".to_string(), Value::String(format!("{}{}", conversions::column_index_to_name(*col), row)));
+        if let Value::Object(comment) = comment.to_value() {
+            record.extend(comment);
+        }
+        record.insert("value".to_string(), reader.read(sheet, *row, *col)?);
+        records.push(Value::Object(record));
+    }
+
+    let mut results = IndexMap::new();
+    results.insert("data".to_string(), Value::Array(records));
+    Ok(results)
+}
@@ -0,0 +1,171 @@
+use anyhow::{Result, Error};
+use calamine::{Range, Data};
+use serde_json::{Map, Value};
+use regex::Regex;
+use crate::utils::{conversions, manipulations};
+use crate::utils::context::ExtractionContext;
+
+/// A check against a cell value: "equals", "matches", "one_of" or "is_empty".
+pub enum Check {
+    IsEmpty(bool),
+    Equals(Value),
+    Matches(Regex),
+    OneOf(Vec<Value>),
+}
+
+impl Check {
+    /// Whether the object has one of the check keys.
+    pub fn is_given(obj: &Map<String, Value>) -> bool {
+        ["equals", "matches", "one_of", "is_empty"].iter().any(|key| obj.contains_key(*key))
+    }
+
+    pub fn parse(obj: &Map<String, Value>) -> Result<Self, Error> {
+        let check = if let Some(expected) = obj.get("equals") {
+            Check::Equals(expected.clone())
+        } else if let Some(pattern) = obj.get("matches") {
+            let pattern = pattern.as_str().ok_or_else(|| Error::msg("Invalid 'matches' pattern in condition"))?;
+            Check::Matches(Regex::new(pattern)?)
+        } else if let Some(values) = obj.get("one_of") {
+            let values = values.as_array().ok_or_else(|| Error::msg("Invalid 'one_of' list in condition"))?;
+            Check::OneOf(values.clone())
+        } else if let Some(is_empty) = obj.get("is_empty") {
+            Check::IsEmpty(is_empty.as_bool().ok_or_else(|| Error::msg("Invalid 'is_empty' value in condition"))?)
+        } else {
+            return Err(Error::msg("Condition requires one of 'equals', 'matches', 'one_of' or 'is_empty'"));
+        };
+        Ok(check)
+    }
+
+    pub fn is_met(&self, cell_value: &Value) -> bool {
+        match self {
+            Check::IsEmpty(expected) => is_empty(cell_value) == *expected,
+            Check::Equals(expected) => values_equal(cell_value, expected),
+            Check::Matches(regex) => match cell_value {
+                Value::Null => false,
+                Value::String(s) => regex.is_match(s),
+                other => regex.is_match(&other.to_string()),
+            },
+            Check::OneOf(values) => values.iter().any(|expected| values_equal(cell_value, expected)),
+        }
+    }
+}
+
+/// A check against the value of a single cell, e.g. {"cell": "C3", "matches": "^Total"}.
+/// The cell may be on another sheet, e.g. {"cell": "Cover!B3", "equals": "Final"}.
+pub struct Condition {
+    sheet: Option<String>,
+    row: u32,
+    col: u32,
+    check: Check,
+}
+
+impl Condition {
+    /// Shorthand used by the *_if_null keys: a cell address that must be empty.
+    pub fn is_null(cell_address: &str) -> Result<Self, Error> {
+        let (sheet, row, col) = parse_cell(cell_address)?;
+        Ok(Condition { sheet, row, col, check: Check::IsEmpty(true) })
+    }
+
+    pub fn parse(obj: &Map<String, Value>) -> Result<Self, Error> {
+        let cell_address = obj.get("cell")
+            .and_then(Value::as_str)
+            .ok_or_else(|| Error::msg("Missing 'cell' in condition"))?;
+        let (sheet, row, col) = parse_cell(cell_address)?;
+        Ok(Condition { sheet, row, col, check: Check::parse(obj)? })
+    }
+
+    pub fn is_met(&self, sheet: &Range<Data>, context: &ExtractionContext) -> Result<bool, Error> {
+        let sheet = context.worksheet(sheet, self.sheet.as_deref());
+        let (cell_value, _) = manipulations::extract_cell_value(sheet, self.row, self.col, false)?;
+        Ok(self.check.is_met(&cell_value.unwrap_or(Value::Null)))
+    }
+}
+
+fn parse_cell(cell_address: &str) -> Result<(Option<String>, u32, u32), Error> {
+    let reference = conversions::parse_reference(cell_address)?;
+    let (row, col) = reference.cell().ok_or_else(|| Error::msg(format!("Invalid cell address '{}'", cell_address)))?;
+    Ok((reference.sheet, row, col))
+}
+
+fn is_empty(value: &Value) -> bool {
+    match value {
+        Value::Null => true,
+        Value::String(s) => s.is_empty(),
+        _ => false,
+    }
+}
+
+// Numbers are compared by value, since calamine reads most numeric cells as floats
+fn values_equal(cell_value: &Value, expected: &Value) -> bool {
+    match (cell_value, expected) {
+        (Value::Number(a), Value::Number(b)) => a.as_f64() == b.as_f64(),
+        _ => cell_value == expected,
+    }
+}
+
+/// Parses a condition value, which is either a single condition object or a list of them.
+pub fn parse_conditions(value: &Value) -> Result<Vec<Condition>, Error> {
+    match value {
+        Value::Object(obj) => Ok(vec![Condition::parse(obj)?]),
+        Value::Array(arr) => arr.iter()
+            .map(|v| v.as_object()
+                .ok_or_else(|| Error::msg("Each condition should be a JSON object"))
+                .and_then(Condition::parse))
+            .collect(),
+        _ => Err(Error::msg("Invalid condition format")),
+    }
+}
+
+/// What to do with a sheet after evaluating its rules.
+#[derive(PartialEq)]
+pub enum RuleOutcome {
+    Extract,
+    Skip,
+    Stop,
+}
+
+/// Skip and stop rules, read from either an extraction block or a single extraction.
+#[derive(Default)]
+pub struct SheetRules {
+    skip: Vec<Condition>,
+    stop: Vec<Condition>,
+}
+
+impl SheetRules {
+    pub fn parse(map: &Map<String, Value>) -> Result<Self, Error> {
+        let mut rules = SheetRules::default();
+        // "break_if_null" is kept as an alias of "skip_sheet_if_null"
+        for key in ["skip_sheet_if_null", "break_if_null"] {
+            if let Some(value) = map.get(key) {
+                let cell_address = value.as_str().ok_or_else(|| Error::msg(format!("Invalid '{}' cell address", key)))?;
+                rules.skip.push(Condition::is_null(cell_address)?);
+            }
+        }
+        if let Some(value) = map.get("stop_if_null") {
+            let cell_address = value.as_str().ok_or_else(|| Error::msg("Invalid 'stop_if_null' cell address"))?;
+            rules.stop.push(Condition::is_null(cell_address)?);
+        }
+        if let Some(value) = map.get("skip_sheet_if") {
+            rules.skip.extend(parse_conditions(value)?);
+        }
+        if let Some(value) = map.get("stop_if") {
+            rules.stop.extend(parse_conditions(value)?);
+        }
+        Ok(rules)
+    }
+
+    /// Stop rules take precedence over skip rules. Within each list, any met condition triggers the rule.
+    pub fn evaluate(&self, sheet: &Range<Data>, context: &ExtractionContext) -> Result<RuleOutcome, Error> {
+        for condition in &self.stop {
+            if condition.is_met(sheet, context)? {
+                return Ok(RuleOutcome::Stop);
+            }
+        }
+        for condition in &self.skip {
+            if condition.is_met(sheet, context)? {
+                return Ok(RuleOutcome::Skip);
+            }
+        }
+        Ok(RuleOutcome::Extract)
+    }
+}
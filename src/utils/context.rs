@@ -0,0 +1,160 @@
+use anyhow::{Result, Error};
+use calamine::{Data, Range, Table};
+use serde_json::Value;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::OnceLock;
+use crate::utils::annotations::{Include, SheetAnnotations};
+use crate::utils::conversions::{self, Reference};
+use crate::utils::styles::SheetStyles;
+use crate::utils::validation::Validations;
+use crate::utils::xlsx_parts::XlsxParts;
+
+/// Resolves a defined name (case-insensitive, like Excel) or an A1 reference, before any extraction context exists.
+pub fn resolve_reference(defined_names: &[(String, String)], reference: &str) -> Result<Reference, Error> {
+    let defined = defined_names.iter().find(|(defined, _)| defined.eq_ignore_ascii_case(reference));
+    match defined {
+        Some((_, formula)) => conversions::parse_reference(formula.trim_start_matches('='))
+            .map_err(|e| Error::msg(format!("Unsupported named range '{}': {}", reference, e))),
+        None => conversions::parse_reference(reference),
+    }
+}
+
+/// Workbook level information available to the extraction functions, besides the current sheet.
+pub struct ExtractionContext<'a> {
+    pub sheet_name: &'a str,
+    pub defined_names: &'a [(String, String)],
+    pub tables: &'a [Table<Data>],
+    /// Other sheets of the workbook named in sheet-qualified references, e.g. "Cover!B3".
+    pub sheets: &'a HashMap<String, Range<Data>>,
+    /// The xml parts of an xlsx workbook, when an extraction reads comments, hyperlinks, rich text, data validations, form controls or cell styles.
+    pub parts: Option<&'a XlsxParts>,
+    /// Data-quality warnings raised by the current extraction, collected into the file report.
+    pub warnings: RefCell<Vec<String>>,
+}
+
+impl ExtractionContext<'_> {
+    /// Resolves a defined name (case-insensitive, like Excel) or an A1 reference.
+    /// The reference may point to another sheet; use `worksheet` to get the range it refers to.
+    pub fn resolve_reference(&self, reference: &str) -> Result<Reference, Error> {
+        resolve_reference(self.defined_names, reference)
+    }
+
+    /// Returns the range a reference reads from: the current sheet when the reference has no sheet name
+    /// (or names the current sheet), otherwise the other sheet of the same workbook. Sheet names are
+    /// case-insensitive, like Excel. A missing sheet is read as an empty sheet, with a warning.
+    pub fn worksheet<'b>(&'b self, current: &'b Range<Data>, sheet_name: Option<&str>) -> &'b Range<Data> {
+        match sheet_name {
+            None => current,
+            Some(name) if name.eq_ignore_ascii_case(self.sheet_name) => current,
+            Some(name) => match self.sheets.iter().find(|(sheet, _)| sheet.eq_ignore_ascii_case(name)) {
+                Some((_, range)) => range,
+                None => {
+                    self.warn(format!("Sheet '{}' not found, its cells are read as empty", name));
+                    static EMPTY: OnceLock<Range<Data>> = OnceLock::new();
+                    EMPTY.get_or_init(Range::empty)
+                },
+            },
+        }
+    }
+
+    // Row and column ranges are combined with other instructions on the current sheet, so they cannot point elsewhere
+    fn resolve_local(&self, reference: &str) -> Result<Reference, Error> {
+        let parsed = self.resolve_reference(reference)?;
+        match &parsed.sheet {
+            Some(sheet) if !sheet.eq_ignore_ascii_case(self.sheet_name) => {
+                Err(Error::msg(format!("Reference '{}' refers to sheet '{}', not '{}'", reference, sheet, self.sheet_name)))
+            },
+            _ => Ok(parsed),
+        }
+    }
+
+    /// Resolves a single cell reference or defined name to its sheet name (if any) and (1-based row, 0-based column) pair.
+    pub fn resolve_cell(&self, reference: &str) -> Result<(Option<String>, u32, u32), Error> {
+        let parsed = self.resolve_reference(reference)?;
+        let (row, col) = parsed.cell()
+            .ok_or_else(|| Error::msg(format!("Reference '{}' is not a single cell", reference)))?;
+        Ok((parsed.sheet, row, col))
+    }
+
+    /// Reads a row range given as [start, end] or as a reference ("5:15", "B5:F15" or a defined name).
+    pub fn row_range(&self, value: &Value) -> Result<(u32, u32), Error> {
+        match value {
+            Value::Array(arr) => {
+                let start_row = arr.first().and_then(Value::as_u64)
+                    .ok_or_else(|| Error::msg("Missing 'start_row' in 'row_range'"))? as u32;
+                let end_row = arr.get(1).and_then(Value::as_u64)
+                    .ok_or_else(|| Error::msg("Missing 'end_row' in 'row_range'"))? as u32;
+                Ok((start_row, end_row))
+            },
+            Value::String(s) => {
+                let reference = self.resolve_local(s)?;
+                match (reference.start_row, reference.end_row) {
+                    (Some(start_row), Some(end_row)) => Ok((start_row, end_row)),
+                    _ => Err(Error::msg(format!("'row_range' reference '{}' has no rows", s))),
+                }
+            },
+            _ => Err(Error::msg("Missing or invalid 'row_range'")),
+        }
+    }
+
+    /// Reads a column range given as [start, end] (letters or 0-based numbers) or as a reference ("B:F", "B5:F15" or a defined name).
+    pub fn column_range(&self, value: &Value) -> Result<(u32, u32), Error> {
+        let column_index = |value: Option<&Value>| match value {
+            Some(Value::Number(n)) => n.as_u64().map(|n| n as u32).ok_or_else(|| Error::msg("Invalid 'column_range'")),
+            Some(Value::String(s)) => conversions::column_name_to_index(s),
+            _ => Err(Error::msg("Invalid 'column_range'")),
+        };
+        match value {
+            Value::Array(arr) => Ok((column_index(arr.first())?, column_index(arr.get(1))?)),
+            Value::String(s) => {
+                let reference = self.resolve_local(s)?;
+                match (reference.start_col, reference.end_col) {
+                    (Some(start_col), Some(end_col)) => Ok((start_col, end_col)),
+                    _ => Err(Error::msg(format!("'column_range' reference '{}' has no columns", s))),
+                }
+            },
+            _ => Err(Error::msg("Missing or invalid 'column_range'")),
+        }
+    }
+
+    pub fn warn(&self, message: String) {
+        println!("Warning: {}: {}", self.sheet_name, message);
+        self.warnings.borrow_mut().push(message);
+    }
+
+    /// The xml parts of the workbook, for features only available in xlsx files. Other formats get None, with a warning.
+    pub fn xlsx_parts(&self, feature: &str) -> Option<&XlsxParts> {
+        if self.parts.is_none() {
+            self.warn(format!("{} are only available in xlsx files", feature));
+        }
+        self.parts
+    }
+
+    /// Reads the comments, hyperlinks, rich text, data validations or cell styles of the current sheet, or of another sheet of the workbook.
+    /// Other formats than xlsx have no annotations, so the annotations are returned as null.
+    pub fn annotations(&self, sheet_name: Option<&str>, include: &Include) -> Result<SheetAnnotations, Error> {
+        let parts = match self.xlsx_parts("Comments, hyperlinks, rich text, data validations and cell styles") {
+            Some(parts) => parts,
+            None => return Ok(SheetAnnotations::default()),
+        };
+        let sheet_name = sheet_name.unwrap_or(self.sheet_name);
+        let mut annotations = SheetAnnotations::load(parts, sheet_name, include)?;
+        if include.validation {
+            annotations.validations = Validations::load(parts, sheet_name, self.defined_names, self.sheets)?;
+        }
+        Ok(annotations)
+    }
+
+    /// Reads the cell styles of the current sheet, or of another sheet of the workbook. None for other formats than xlsx.
+    pub fn styles(&self, sheet_name: Option<&str>) -> Result<Option<SheetStyles>, Error> {
+        match self.xlsx_parts("Cell styles") {
+            Some(parts) => SheetStyles::load(parts, sheet_name.unwrap_or(self.sheet_name)).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    pub fn table(&self, name: &str) -> Option<&Table<Data>> {
+        self.tables.iter().find(|table| table.name().eq_ignore_ascii_case(name))
+    }
+}
@@ -0,0 +1,260 @@
+use anyhow::{Result, Error};
+use calamine::{Range, Data};
+use serde_json::{Map, Value};
+use indexmap::IndexMap;
+use crate::utils::conversions;
+use crate::utils::context::ExtractionContext;
+use crate::utils::reader::CellReader;
+use crate::utils::xlsx_parts::{Element, XlsxParts};
+
+/// A form control (or ActiveX control) of a sheet, merged from the worksheet, its control properties and its VML drawing.
+#[derive(Default)]
+struct Control {
+    shape_id: Option<u32>,
+    name: Option<String>,
+    kind: String,
+    caption: Option<String>,
+    // Null for controls without a checked state, or when a check box is mixed
+    checked: Value,
+    linked_cell: Option<String>,
+    selected: Option<u64>,
+}
+
+// Form control types, as written in the control properties ("CheckBox") or the VML drawing ("Checkbox")
+fn form_control_kind(object_type: &str) -> String {
+    match object_type.to_lowercase().as_str() {
+        "checkbox" => "check_box",
+        "radio" => "option_button",
+        "drop" => "drop_down",
+        "list" => "list_box",
+        "button" => "button",
+        "label" => "label",
+        "gbox" => "group_box",
+        "spin" => "spinner",
+        "scroll" => "scroll_bar",
+        "editbox" | "edit" => "edit_box",
+        other => return other.to_string(),
+    }.to_string()
+}
+
+// ActiveX controls are identified by the class id of their Microsoft Forms 2.0 type
+fn activex_kind(class_id: &str) -> &'static str {
+    match class_id.trim_matches(|c| c == '{' || c == '}').to_uppercase().as_str() {
+        "8BD21D40-EC42-11CE-9E0D-00AA006002F5" => "check_box",
+        "8BD21D50-EC42-11CE-9E0D-00AA006002F5" => "option_button",
+        "8BD21D60-EC42-11CE-9E0D-00AA006002F5" => "toggle_button",
+        "8BD21D30-EC42-11CE-9E0D-00AA006002F5" => "combo_box",
+        "8BD21D20-EC42-11CE-9E0D-00AA006002F5" => "list_box",
+        "8BD21D10-EC42-11CE-9E0D-00AA006002F5" => "text_box",
+        "D7053240-CE69-11CD-A777-00DD01143C57" => "command_button",
+        "978C9E23-D4B0-11CE-BF2D-00AA003F40D0" => "label",
+        _ => "activex",
+    }
+}
+
+fn is_checkable(kind: &str) -> bool {
+    matches!(kind, "check_box" | "option_button" | "toggle_button")
+}
+
+// The number of a VML shape id, e.g. 1025 for "_x0000_s1025", which matches the shapeId of the control
+fn shape_number(id: &str) -> Option<u32> {
+    id.rsplit(|c: char| !c.is_ascii_digit()).next()?.parse().ok()
+}
+
+// The text of an element and its descendants, with line breaks for <br>
+fn element_text(element: &Element) -> String {
+    let mut text = element.text.clone();
+    for child in &element.children {
+        if child.name == "br" {
+            text.push('\n');
+        }
+        text.push_str(&element_text(child));
+    }
+    text
+}
+
+fn descendants<'a>(element: &'a Element, name: &str, found: &mut Vec<&'a Element>) {
+    for child in &element.children {
+        if child.name == name {
+            found.push(child);
+        }
+        descendants(child, name, found);
+    }
+}
+
+// Controls of older files are only described in the VML drawing, newer files also write them there for compatibility
+fn vml_controls(parts: &XlsxParts, sheet_part: &str) -> Result<Vec<Control>, Error> {
+    let mut controls = Vec::new();
+    for path in parts.related(sheet_part, "vmlDrawing")? {
+        let mut shapes = Vec::new();
+        let drawing = parts.xml(&path)?;
+        descendants(&drawing, "shape", &mut shapes);
+        for shape in shapes {
+            let Some(client) = shape.child("ClientData") else { continue };
+            let kind = match client.attr("ObjectType") {
+                // Comments and ActiveX placeholder pictures are also VML shapes
+                Some("Note") | Some("Pict") | None => continue,
+                Some(object_type) => form_control_kind(object_type),
+            };
+            let property = |name: &str| client.child(name).map(|property| property.text.trim().to_string());
+            let checked = match (is_checkable(&kind), property("Checked").as_deref()) {
+                (false, _) | (true, Some("2")) => Value::Null,
+                (true, checked) => Value::Bool(checked == Some("1")),
+            };
+            let caption = shape.child("textbox")
+                .map(|textbox| element_text(textbox).replace('\u{a0}', " ").trim().to_string())
+                .filter(|caption| !caption.is_empty());
+            controls.push(Control {
+                shape_id: shape.attr("id").and_then(shape_number),
+                name: None,
+                caption,
+                checked,
+                linked_cell: property("FmlaLink").filter(|link| !link.is_empty()),
+                selected: property("Sel").and_then(|selected| selected.parse().ok()),
+                kind,
+            });
+        }
+    }
+    Ok(controls)
+}
+
+// Controls listed in the worksheet, with the properties of form controls, or the property bag of ActiveX controls
+fn worksheet_controls(parts: &XlsxParts, sheet_name: &str, sheet_part: &str) -> Result<Vec<Control>, Error> {
+    let worksheet = parts.worksheet(sheet_name, false)?;
+    let mut elements = Vec::new();
+    if let Some(list) = worksheet.child("controls") {
+        descendants(list, "control", &mut elements);
+    }
+    let relationships = parts.relationships(sheet_part)?;
+    let mut controls: Vec<Control> = Vec::new();
+    for element in elements {
+        let shape_id = element.attr("shapeId").and_then(|id| id.parse().ok());
+        // Controls are written twice, for Excel 2010+ and as a fallback for older versions
+        if shape_id.is_some() && controls.iter().any(|control| control.shape_id == shape_id) {
+            continue;
+        }
+        let mut control = Control { shape_id, name: element.attr("name").map(str::to_string), ..Control::default() };
+        let relationship = element.attr("id").and_then(|id| relationships.iter().find(|relationship| relationship.id == id));
+        match relationship {
+            Some(relationship) if relationship.kind == "ctrlProp" && !relationship.external => {
+                let properties = parts.xml(&relationship.target)?;
+                control.kind = form_control_kind(properties.attr("objectType").unwrap_or_default());
+                if is_checkable(&control.kind) {
+                    control.checked = match properties.attr("checked") {
+                        Some("Mixed") => Value::Null,
+                        checked => Value::Bool(checked == Some("Checked")),
+                    };
+                }
+                control.linked_cell = properties.attr("fmlaLink").map(str::to_string);
+                control.selected = properties.attr("sel").and_then(|selected| selected.parse().ok());
+            },
+            Some(relationship) if relationship.kind == "control" && !relationship.external => {
+                // Most ActiveX controls store their properties in a binary part, only the property bag form can be read
+                let ocx = parts.xml(&relationship.target)?;
+                control.kind = activex_kind(ocx.attr("classid").unwrap_or_default()).to_string();
+                let property = |name: &str| ocx.children("ocxPr")
+                    .find(|property| property.attr("name").is_some_and(|property_name| property_name.eq_ignore_ascii_case(name)))
+                    .and_then(|property| property.attr("value"))
+                    .map(str::to_string);
+                control.caption = property("Caption");
+                if is_checkable(&control.kind) {
+                    control.checked = match property("Value").as_deref() {
+                        Some("1") | Some("True") | Some("true") => Value::Bool(true),
+                        Some("0") | Some("False") | Some("false") => Value::Bool(false),
+                        _ => Value::Null,
+                    };
+                }
+                control.linked_cell = property("LinkedCell");
+            },
+            // Described in the VML drawing only
+            _ => (),
+        }
+        controls.push(control);
+    }
+    Ok(controls)
+}
+
+// The controls of a sheet, merged from the worksheet and its VML drawings
+fn sheet_controls(parts: &XlsxParts, sheet_name: &str) -> Result<Vec<Control>, Error> {
+    let sheet_part = parts.sheet_part(sheet_name)?;
+    let mut controls = worksheet_controls(parts, sheet_name, sheet_part)?;
+    for drawn in vml_controls(parts, sheet_part)? {
+        match controls.iter_mut().find(|control| control.shape_id.is_some() && control.shape_id == drawn.shape_id) {
+            Some(control) if control.kind.is_empty() => *control = Control { name: control.name.take(), ..drawn },
+            // The caption of form controls is only in the drawing
+            Some(control) => control.caption = control.caption.take().or(drawn.caption),
+            None => controls.push(drawn),
+        }
+    }
+    Ok(controls)
+}
+
+// Parses a linked cell, written as a formula such as "$B$2" or "Answers!$B$2"
+fn linked_reference(control: &Control) -> Option<conversions::Reference> {
+    control.linked_cell.as_deref()
+        .and_then(|link| conversions::parse_reference(link.trim_start_matches('=')).ok())
+        .filter(|reference| reference.cell().is_some())
+}
+
+/// The other sheets the controls of the given sheets are linked to, so they can be loaded with the other
+/// referenced sheets. Sheets whose controls cannot be read are left out.
+pub fn linked_sheets(parts: &XlsxParts, sheet_names: &[String]) -> Vec<String> {
+    let mut sheets = Vec::new();
+    for sheet_name in sheet_names {
+        let Ok(controls) = sheet_controls(parts, sheet_name) else {
+            continue;
+        };
+        for sheet in controls.iter().filter_map(linked_reference).filter_map(|reference| reference.sheet) {
+            if !sheets.contains(&sheet) {
+                sheets.push(sheet);
+            }
+        }
+    }
+    sheets
+}
+
+/// Lists the form controls (check boxes, option buttons, drop-downs...) and ActiveX controls of the sheet, whose state
+/// is not stored in the cells: their name, type, caption, checked state, linked cell and the value of the linked cell.
+pub fn extract_controls(sheet: &Range<Data>, instructions: &Map<String, Value>, context: &ExtractionContext) -> Result<IndexMap<String, Value>, Error> {
+    let parts = match context.xlsx_parts("Form controls") {
+        Some(parts) => parts,
+        None => return Ok(IndexMap::new()),
+    };
+    let reader = CellReader::parse(instructions)?;
+    let controls = sheet_controls(parts, context.sheet_name)?;
+
+    let mut records = Vec::new();
+    for control in controls {
+        let (linked_cell, value) = match linked_reference(&control) {
+            Some(reference) => {
+                let (row, col) = reference.cell().unwrap();
+                let address = format!("{}{}", conversions::column_index_to_name(col), row);
+                let linked_cell = match reference.sheet.as_deref() {
+                    Some(sheet_name) => format!("{}!{}", sheet_name, address),
+                    None => address,
+                };
+                // A linked cell holding an error, such as #N/A, has no value
+                let value = reader.read(context.worksheet(sheet, reference.sheet.as_deref()), row, col)
+                    .unwrap_or_else(|e| {
+                        context.warn(format!("Linked cell {} of control '{}': {}", linked_cell, control.name.as_deref().unwrap_or_default(), e));
+                        Value::Null
+                    });
+                (Value::String(linked_cell), value)
+            },
+            None => (Value::Null, Value::Null),
+        };
+        let mut record = Map::new();
+        record.insert("name".to_string(), control.name.map_or(Value::Null, Value::String));
+        record.insert("type".to_string(), Value::String(control.kind));
+        record.insert("caption".to_string(), control.caption.map_or(Value::Null, Value::String));
+        record.insert("checked".to_string(), control.checked);
+        record.insert("linked_cell".to_string(), linked_cell);
+        record.insert("value".to_string(), value);
+        record.insert("selected".to_string(), control.selected.map_or(Value::Null, Value::from));
+        records.push(Value::Object(record));
+    }
+
+    let mut results = IndexMap::new();
+    results.insert("data".to_string(), Value::Array(records));
+    Ok(results)
+}
@@ -1,5 +1,6 @@
 use chrono::{NaiveDate, Duration};
 use anyhow::{Result, Error};
+use calamine::{Range, Data};
 use std::path::Path;
 
 /// Converts an Excel date-time float to a human-readable ISO 8601 string.
@@ -22,33 +23,123 @@ pub fn excel_datetime(excel_date: f64) -> Result<String, Error> {
     Ok(datetime.format("%Y-%m-%d %H:%M:%S").to_string())
 }
 
-pub fn address_to_row_col(cell_address: &str) -> Result<(u32, u32), Error> {
-    let split_at = cell_address.chars().position(|c| c.is_digit(10)).ok_or_else(|| Error::msg("Invalid cell address format"))?;
-    let (col_str, row_str) = cell_address.split_at(split_at);
+const MAX_ROWS: u32 = 1_048_576;
+const MAX_COLUMNS: u32 = 16_384;
 
-    let col = col_str.chars().rev().enumerate().try_fold(0u32, |acc, (i, c)| {
-        if let Some(digit) = c.to_digit(36) {
-            Ok(acc + (digit - 10) * 26u32.pow(i as u32))
-        } else {
-            Err(Error::msg("Invalid column label"))
+/// A parsed A1 reference, e.g. "B4", "$B$4", "B5:F15", "B:F", "5:15" or "'Cost Sheet'!B5:F15".
+/// Rows are 1-based and columns 0-based, like the rest of the extraction code.
+/// Whole column references have no rows, and whole row references have no columns.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reference {
+    pub sheet: Option<String>,
+    pub start_row: Option<u32>,
+    pub end_row: Option<u32>,
+    pub start_col: Option<u32>,
+    pub end_col: Option<u32>,
+}
+
+impl Reference {
+    /// Returns the (row, col) pair if the reference is a single cell.
+    pub fn cell(&self) -> Option<(u32, u32)> {
+        match (self.start_row, self.start_col) {
+            (Some(row), Some(col)) if self.end_row == Some(row) && self.end_col == Some(col) => Some((row, col)),
+            _ => None,
         }
-    })?;
+    }
 
-    let row: u32 = row_str.parse().map_err(|_| Error::msg("Invalid row number"))?;
+    /// Returns (start_row, end_row, start_col, end_col), filling whole rows or columns from the used range of the sheet.
+    pub fn bounds(&self, sheet: &Range<Data>) -> (u32, u32, u32, u32) {
+        let (used_end_row, used_end_col) = sheet.end().unwrap_or((0, 0));
+        (
+            self.start_row.unwrap_or(1),
+            self.end_row.unwrap_or(used_end_row + 1),
+            self.start_col.unwrap_or(0),
+            self.end_col.unwrap_or(used_end_col),
+        )
+    }
+}
 
-    // Adjust for 0-based indexing used by Calamine
+// Parses one side of a reference ("$B$4", "B", "4") into an optional row and column
+fn parse_reference_part(part: &str) -> Result<(Option<u32>, Option<u32>), Error> {
+    let part = part.replace('$', "");
+    let split_at = part.find(|c: char| c.is_ascii_digit()).unwrap_or(part.len());
+    let (col_str, row_str) = part.split_at(split_at);
+    if col_str.is_empty() && row_str.is_empty() {
+        return Err(Error::msg("Invalid cell address format"));
+    }
+    let col = if col_str.is_empty() { None } else { Some(column_name_to_index(col_str)?) };
+    let row = if row_str.is_empty() {
+        None
+    } else {
+        let row: u32 = row_str.parse().map_err(|_| Error::msg(format!("Invalid row number '{}'", row_str)))?;
+        if row == 0 || row > MAX_ROWS {
+            return Err(Error::msg(format!("Row number '{}' is out of range", row_str)));
+        }
+        Some(row)
+    };
     Ok((row, col))
 }
 
+/// Parses an A1 reference, with optional absolute markers, range and sheet name.
+pub fn parse_reference(reference: &str) -> Result<Reference, Error> {
+    let reference = reference.trim();
+    let (sheet, address) = match reference.rsplit_once('!') {
+        Some((sheet, address)) => {
+            let sheet = match sheet.strip_prefix('\'').and_then(|s| s.strip_suffix('\'')) {
+                Some(quoted) => quoted.replace("''", "'"),
+                None => sheet.to_string(),
+            };
+            (Some(sheet), address)
+        },
+        None => (None, reference),
+    };
+    let (start, end) = address.split_once(':').unwrap_or((address, address));
+    let (start_row, start_col) = parse_reference_part(start)?;
+    let (end_row, end_col) = parse_reference_part(end)?;
+    if start_row.is_some() != end_row.is_some() || start_col.is_some() != end_col.is_some() {
+        return Err(Error::msg(format!("Invalid range '{}'", address)));
+    }
+    // Normalize reversed ranges such as "F15:B5"
+    Ok(Reference {
+        sheet,
+        start_row: start_row.min(end_row),
+        end_row: start_row.max(end_row),
+        start_col: start_col.min(end_col),
+        end_col: start_col.max(end_col),
+    })
+}
+
 pub fn column_name_to_index(column_name: &str) -> Result<u32, Error> {
-    let mut col_idx = 0;
-    for (i, char) in column_name.chars().rev().enumerate() {
-        let digit = char as u32 - 'A' as u32 + 1;
-        col_idx += digit * 26u32.pow(i as u32);
+    let column_name = column_name.trim().trim_start_matches('$');
+    if column_name.is_empty() {
+        return Err(Error::msg("Empty column label"));
+    }
+    let mut col_idx: u32 = 0;
+    for char in column_name.chars() {
+        if !char.is_ascii_alphabetic() {
+            return Err(Error::msg(format!("Invalid column label '{}'", column_name)));
+        }
+        let digit = char.to_ascii_uppercase() as u32 - 'A' as u32 + 1;
+        col_idx = col_idx * 26 + digit;
+        if col_idx > MAX_COLUMNS {
+            return Err(Error::msg(format!("Column label '{}' is out of range", column_name)));
+        }
     }
     Ok(col_idx - 1)  // converting to 0-based index
 }
 
+/// Converts a 0-based column index to its column name, e.g. 0 -> "A" and 27 -> "AB".
+pub fn column_index_to_name(index: u32) -> String {
+    let mut name = Vec::new();
+    let mut remaining = index + 1;
+    while remaining > 0 {
+        let digit = (remaining - 1) % 26;
+        name.push((b'A' + digit as u8) as char);
+        remaining = (remaining - 1) / 26;
+    }
+    name.iter().rev().collect()
+}
+
 // Helper function to extract the base filename without extension
 pub fn extract_filename(path: &str) -> String {
     Path::new(path)
@@ -57,3 +148,65 @@ pub fn extract_filename(path: &str) -> String {
         .unwrap_or_default()
         .to_string()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reference(sheet: Option<&str>, rows: Option<(u32, u32)>, cols: Option<(u32, u32)>) -> Reference {
+        Reference {
+            sheet: sheet.map(str::to_string),
+            start_row: rows.map(|(start, _)| start),
+            end_row: rows.map(|(_, end)| end),
+            start_col: cols.map(|(start, _)| start),
+            end_col: cols.map(|(_, end)| end),
+        }
+    }
+
+    #[test]
+    fn parse_reference_reads_cells_with_or_without_absolute_markers() {
+        for address in ["B4", "$B$4", "$B4", "B$4", "b4", " B4 "] {
+            let parsed = parse_reference(address).unwrap();
+            assert_eq!(parsed, reference(None, Some((4, 4)), Some((1, 1))), "{}", address);
+            assert_eq!(parsed.cell(), Some((4, 1)));
+        }
+        assert_eq!(parse_reference("xfd1048576").unwrap().cell(), Some((1_048_576, 16_383)));
+    }
+
+    #[test]
+    fn parse_reference_normalizes_ranges() {
+        let expected = reference(None, Some((5, 15)), Some((1, 5)));
+        assert_eq!(parse_reference("B5:F15").unwrap(), expected);
+        assert_eq!(parse_reference("F15:B5").unwrap(), expected);
+        assert_eq!(parse_reference("$b$5:$f$15").unwrap(), expected);
+        // Any two opposite corners give the same range
+        assert_eq!(parse_reference("B15:F5").unwrap(), expected);
+        assert_eq!(parse_reference("B5:F15").unwrap().cell(), None);
+    }
+
+    #[test]
+    fn parse_reference_reads_whole_rows_and_columns() {
+        assert_eq!(parse_reference("B:F").unwrap(), reference(None, None, Some((1, 5))));
+        assert_eq!(parse_reference("f:b").unwrap(), reference(None, None, Some((1, 5))));
+        assert_eq!(parse_reference("5:15").unwrap(), reference(None, Some((5, 15)), None));
+        assert_eq!(parse_reference("$15:$5").unwrap(), reference(None, Some((5, 15)), None));
+        // A column and a row do not make a range
+        assert!(parse_reference("B:5").is_err());
+        assert!(parse_reference("B5:F").is_err());
+    }
+
+    #[test]
+    fn parse_reference_reads_sheet_names() {
+        assert_eq!(parse_reference("Cover!B3").unwrap(), reference(Some("Cover"), Some((3, 3)), Some((1, 1))));
+        assert_eq!(parse_reference("'Cost Sheet'!B5:F15").unwrap(), reference(Some("Cost Sheet"), Some((5, 15)), Some((1, 5))));
+        assert_eq!(parse_reference("'Cost!Sheet'!A1").unwrap(), reference(Some("Cost!Sheet"), Some((1, 1)), Some((0, 0))));
+        assert_eq!(parse_reference("'O''Brien'!A1").unwrap().sheet.as_deref(), Some("O'Brien"));
+    }
+
+    #[test]
+    fn parse_reference_rejects_invalid_addresses() {
+        for address in ["XFE1", "A0", "A1048577", "AAAA1", "A1B", "B-4", "", ":"] {
+            assert!(parse_reference(address).is_err(), "{}", address);
+        }
+    }
+}
@@ -3,31 +3,27 @@ use calamine::{Range, Data};
 use serde_json::{Map, Value};
 use indexmap::IndexMap;
 use crate::utils::{conversions, manipulations};
+use crate::utils::context::ExtractionContext;
+use crate::utils::reader::{CellReader, NullPolicy};
 
 pub fn extract_dataframe(
     sheet: &Range<Data>,
-    instructions: &Map<String, Value>
+    instructions: &Map<String, Value>,
+    context: &ExtractionContext
 ) -> Result<IndexMap<String, Value>, Error> {
-    // Extracting row range
-    let row_range = instructions.get("row_range")
-        .and_then(Value::as_array)
-        .ok_or_else(|| Error::msg("Missing or invalid 'row_range'"))?;
-    let start_row = row_range.get(0).and_then(Value::as_u64).unwrap_or(0) as u32;
-    let end_row = row_range.get(1).and_then(Value::as_u64).unwrap_or(0) as u32;
-
-    // Extracting column range
-    let column_range = instructions.get("column_range")
-        .and_then(Value::as_array)
-        .ok_or_else(|| Error::msg("Missing or invalid 'column_range'"))?;
-    let start_column_index = match column_range.get(0).ok_or_else(|| Error::msg("Invalid 'column_range'"))? {
-        Value::Number(n) => n.as_u64().unwrap() as u32,
-        Value::String(s) => conversions::column_name_to_index(s)?,
-        _ => return Err(Error::msg("Invalid 'start_column' format")),
-    };
-    let end_column_index = match column_range.get(1).ok_or_else(|| Error::msg("Invalid 'column_range'"))? {
-        Value::Number(n) => n.as_u64().unwrap() as u32,
-        Value::String(s) => conversions::column_name_to_index(s)?,
-        _ => return Err(Error::msg("Invalid 'end_column' format")),
+    // A single "range" (e.g. "B5:F15" or "Details!B5:F15") replaces "row_range" and "column_range"
+    let (sheet, (start_row, end_row, start_column_index, end_column_index)) = match instructions.get("range") {
+        Some(range) => {
+            let range = range.as_str().ok_or_else(|| Error::msg("Invalid 'range'"))?;
+            let reference = context.resolve_reference(range)?;
+            let source = context.worksheet(sheet, reference.sheet.as_deref());
+            (source, reference.bounds(source))
+        },
+        None => {
+            let (start_row, end_row) = context.row_range(instructions.get("row_range").unwrap_or(&Value::Null))?;
+            let (start_column_index, end_column_index) = context.column_range(instructions.get("column_range").unwrap_or(&Value::Null))?;
+            (sheet, (start_row, end_row, start_column_index, end_column_index))
+        },
     };
 
     // Extracting header row
@@ -38,16 +34,146 @@ pub fn extract_dataframe(
         _ => return Err(Error::msg("Invalid 'header_row' format")),
     };
     let separator = instructions.get("separator").and_then(Value::as_str).unwrap_or(" ");
-    let mut dataframe: IndexMap<String, Value> = IndexMap::new();
-    for i in start_column_index..=end_column_index {
-        let header_string = manipulations::extract_headers(sheet, &header_indices, i, separator)?;
+    let melt = Melt::parse(instructions)?;
+    let reader = CellReader::parse(instructions)?;
+    let dataframe = build_dataframe(sheet, &header_indices, (start_column_index, end_column_index), (start_row, end_row), separator, &reader, false)?;
+    match melt {
+        Some(melt) => {
+            let mut results = IndexMap::new();
+            results.insert("data".to_string(), Value::Array(melt.apply(&dataframe, &reader)?));
+            Ok(results)
+        },
+        None => Ok(dataframe),
+    }
+}
 
-        let data_array = manipulations::extract_column_data(sheet, i, start_row, end_row)?;
+/// Unpivot options: the id columns are kept on every record, the other columns become (variable, value) pairs.
+struct Melt {
+    id_columns: Vec<String>,
+    var_name: String,
+    value_name: String,
+}
 
-        // Insert the header string and data array into the dataframe
-        dataframe.insert(header_string, data_array);
+impl Melt {
+    fn parse(instructions: &Map<String, Value>) -> Result<Option<Self>, Error> {
+        let (var_name, value_name) = match instructions.get("melt") {
+            None | Some(Value::Bool(false)) => {
+                if instructions.contains_key("id_columns") {
+                    return Err(Error::msg("'id_columns' requires 'melt'"));
+                }
+                return Ok(None);
+            },
+            Some(Value::Bool(true)) => ("variable", "value"),
+            Some(Value::Object(options)) => (
+                options.get("var_name").and_then(Value::as_str).unwrap_or("variable"),
+                options.get("value_name").and_then(Value::as_str).unwrap_or("value"),
+            ),
+            _ => return Err(Error::msg("Invalid 'melt' format")),
+        };
+        let id_columns = match instructions.get("id_columns") {
+            None => Vec::new(),
+            Some(Value::String(column)) => vec![column.clone()],
+            Some(Value::Array(columns)) => columns.iter()
+                .map(|c| c.as_str().map(str::to_string).ok_or_else(|| Error::msg("Invalid column in 'id_columns'")))
+                .collect::<Result<_, Error>>()?,
+            _ => return Err(Error::msg("Invalid 'id_columns' format")),
+        };
+        Ok(Some(Melt { id_columns, var_name: var_name.to_string(), value_name: value_name.to_string() }))
     }
 
+    // Records are ordered like pandas.melt: all rows of the first value column, then the next one
+    // With the "drop" null policy, records without a value are left out
+    fn apply(&self, dataframe: &IndexMap<String, Value>, reader: &CellReader) -> Result<Vec<Value>, Error> {
+        for column in &self.id_columns {
+            if !dataframe.contains_key(column) {
+                return Err(Error::msg(format!("Column '{}' in 'id_columns' not found in the headers", column)));
+            }
+        }
+        let column_values = |column: &Value| column.as_array().cloned().unwrap_or_default();
+        let id_values: Vec<(&String, Vec<Value>)> = self.id_columns.iter()
+            .map(|column| (column, column_values(&dataframe[column])))
+            .collect();
 
-    Ok(dataframe)
+        let mut records = Vec::new();
+        for (header, column) in dataframe {
+            if self.id_columns.contains(header) {
+                continue;
+            }
+            for (row, value) in column_values(column).into_iter().enumerate() {
+                if value.is_null() && matches!(reader.policy(&NullPolicy::Keep), NullPolicy::Drop) {
+                    continue;
+                }
+                let mut record = Map::new();
+                for (id_column, values) in &id_values {
+                    record.insert(id_column.to_string(), values.get(row).cloned().unwrap_or(Value::Null));
+                }
+                record.insert(self.var_name.clone(), Value::String(header.clone()));
+                record.insert(self.value_name.clone(), value);
+                records.push(Value::Object(record));
+            }
+        }
+        Ok(records)
+    }
+}
+
+/// Builds the column-wise dataframe for a block of cells. Rows are 1-based and columns 0-based, like the instructions.
+/// Without header rows, the column names are used as headers. With `detected_headers` (for detected tables), a column
+/// with blank header cells is also named by its letter, and repeated headers get a counter, e.g. "Amount_1".
+pub fn build_dataframe(
+    sheet: &Range<Data>,
+    header_indices: &[u32],
+    (start_column_index, end_column_index): (u32, u32),
+    (start_row, end_row): (u32, u32),
+    separator: &str,
+    reader: &CellReader,
+    detected_headers: bool,
+) -> Result<IndexMap<String, Value>, Error> {
+    let mut columns: Vec<(String, Vec<Value>)> = Vec::new();
+    for i in start_column_index..=end_column_index {
+        let blank_header = detected_headers && header_indices.iter().all(|row| match sheet.get_value((row.saturating_sub(1), i)) {
+            None | Some(Data::Empty) => true,
+            Some(Data::String(text)) => text.trim().is_empty(),
+            _ => false,
+        });
+        let mut header_string = if header_indices.is_empty() || blank_header {
+            conversions::column_index_to_name(i)
+        } else {
+            manipulations::extract_headers(sheet, header_indices, i, separator, &reader.headers)?
+        };
+        if detected_headers {
+            let header = header_string.clone();
+            let mut counter = 1;
+            while columns.iter().any(|(existing, _)| *existing == header_string) {
+                header_string = format!("{}_{}", header, counter);
+                counter += 1;
+            }
+        }
+
+        let data = (start_row..=end_row)
+            .map(|row| reader.read(sheet, row, i))
+            .collect::<Result<Vec<Value>, Error>>()?;
+        columns.push((header_string, data));
+    }
+    Ok(apply_nulls(columns, reader))
+}
+
+/// Applies the null policy to dataframe columns. Nulls are kept by default, and "drop" removes
+/// the rows where every column is empty so that the columns stay aligned.
+pub fn apply_nulls(columns: Vec<(String, Vec<Value>)>, reader: &CellReader) -> IndexMap<String, Value> {
+    let rows = columns.first().map(|(_, data)| data.len()).unwrap_or(0);
+    let drop = matches!(reader.policy(&NullPolicy::Keep), NullPolicy::Drop);
+    let keep_row: Vec<bool> = (0..rows)
+        .map(|row| !drop || columns.iter().any(|(_, data)| !data[row].is_null()))
+        .collect();
+
+    let mut dataframe: IndexMap<String, Value> = IndexMap::new();
+    for (header, data) in columns {
+        let values = data.into_iter()
+            .zip(&keep_row)
+            .filter(|(_, keep)| **keep)
+            .map(|(value, _)| reader.apply(value, &NullPolicy::Keep).unwrap_or(Value::Null))
+            .collect();
+        dataframe.insert(header, Value::Array(values));
+    }
+    dataframe
 }
\ No newline at end of file
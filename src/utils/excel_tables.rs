@@ -0,0 +1,54 @@
+use anyhow::{Result, Error};
+use serde_json::{Map, Value};
+use indexmap::IndexMap;
+use crate::utils::context::ExtractionContext;
+use crate::utils::dataframe;
+use crate::utils::reader::CellReader;
+
+/// Extracts an Excel Table (ListObject) by name, using its own header row. The output matches `dataframe`.
+/// A table on another sheet returns nothing, with a warning, so that a sheet pattern only extracts it from its own sheet.
+pub fn extract_table(context: &ExtractionContext, instructions: &Map<String, Value>) -> Result<IndexMap<String, Value>, Error> {
+    let name = instructions.get("name")
+        .and_then(Value::as_str)
+        .ok_or_else(|| Error::msg("Missing 'name'"))?;
+    let selected_columns: Option<Vec<&str>> = match instructions.get("columns") {
+        None => None,
+        Some(Value::Array(columns)) => Some(columns.iter()
+            .map(|c| c.as_str().ok_or_else(|| Error::msg("Invalid column in 'columns'")))
+            .collect::<Result<_, Error>>()?),
+        _ => return Err(Error::msg("Invalid 'columns' format")),
+    };
+
+    let reader = CellReader::parse(instructions)?;
+
+    let table = match context.table(name) {
+        Some(table) => table,
+        None => {
+            context.warn(format!("Table '{}' not found, skipping extraction", name));
+            return Ok(IndexMap::new());
+        }
+    };
+    if table.sheet_name() != context.sheet_name {
+        context.warn(format!("Table '{}' is on sheet '{}', not '{}', skipping extraction", name, table.sheet_name(), context.sheet_name));
+        return Ok(IndexMap::new());
+    }
+
+    let data = table.data();
+    let (start_row, start_col) = data.start().unwrap_or((0, 0));
+    let rows = data.height() as u32;
+    let mut columns = Vec::new();
+    for (offset, header) in table.columns().iter().enumerate() {
+        if let Some(selected) = &selected_columns {
+            if !selected.contains(&header.as_str()) {
+                continue;
+            }
+        }
+        let col = start_col + offset as u32;
+        // CellReader::read expects 1-based rows
+        let column_data = (start_row + 1..=start_row + rows)
+            .map(|row| reader.read(data, row, col))
+            .collect::<Result<Vec<Value>, Error>>()?;
+        columns.push((header.clone(), column_data));
+    }
+    Ok(dataframe::apply_nulls(columns, &reader))
+}
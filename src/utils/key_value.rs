@@ -0,0 +1,141 @@
+use anyhow::{Result, Error};
+use calamine::{Range, Data};
+use serde_json::{Map, Value};
+use indexmap::IndexMap;
+use crate::utils::{conversions, manipulations};
+use crate::utils::context::ExtractionContext;
+use crate::utils::reader::{CellReader, NullPolicy};
+
+#[derive(Default)]
+struct Normalization {
+    trim: bool,
+    collapse_whitespace: bool,
+    strip_colon: bool,
+}
+
+impl Normalization {
+    // Labels are read untrimmed, so they are only trimmed by default and a list applies exactly the options given
+    fn parse(value: Option<&Value>) -> Result<Self, Error> {
+        let mut normalization = Normalization::default();
+        match value {
+            None | Some(Value::Bool(false)) => normalization.trim = true,
+            Some(Value::Bool(true)) => {
+                normalization.trim = true;
+                normalization.collapse_whitespace = true;
+                normalization.strip_colon = true;
+            },
+            Some(Value::Array(options)) => {
+                for option in options {
+                    match option.as_str() {
+                        Some("trim") => normalization.trim = true,
+                        Some("collapse_whitespace") => normalization.collapse_whitespace = true,
+                        Some("strip_colon") => normalization.strip_colon = true,
+                        _ => return Err(Error::msg(format!("Invalid 'normalize' option {}", option))),
+                    }
+                }
+            },
+            _ => return Err(Error::msg("Invalid 'normalize' format")),
+        }
+        Ok(normalization)
+    }
+
+    fn apply(&self, label: &str) -> String {
+        let mut label = label.to_string();
+        if self.collapse_whitespace {
+            label = label.split_whitespace().collect::<Vec<&str>>().join(" ");
+        }
+        if self.trim {
+            label = label.trim().to_string();
+        }
+        if self.strip_colon {
+            label = label.trim_end().trim_end_matches(':').trim_end().to_string();
+        }
+        label
+    }
+}
+
+/// Scans a row range for "Label: value" pairs, where the value sits in another column on the same row.
+pub fn extract_pairs(sheet: &Range<Data>, instructions: &Map<String, Value>, context: &ExtractionContext) -> Result<IndexMap<String, Value>, Error> {
+    let (start_row, end_row) = context.row_range(instructions.get("row_range").unwrap_or(&Value::Null))?;
+
+    let label_column = instructions.get("label_column")
+        .and_then(Value::as_str)
+        .ok_or_else(|| Error::msg("Missing 'label_column'"))?;
+    let label_index = conversions::column_name_to_index(label_column)?;
+
+    // The value is either in a fixed column or at an offset to the right of the label
+    let value_index = match (instructions.get("value_column"), instructions.get("value_offset")) {
+        (Some(Value::String(column)), _) => conversions::column_name_to_index(column)?,
+        (None, Some(offset)) => {
+            let offset = offset.as_i64().ok_or_else(|| Error::msg("Invalid 'value_offset'"))?;
+            u32::try_from(label_index as i64 + offset).map_err(|_| Error::msg("'value_offset' points outside the sheet"))?
+        },
+        (None, None) => label_index + 1,
+        _ => return Err(Error::msg("Invalid 'value_column'")),
+    };
+
+    let normalization = Normalization::parse(instructions.get("normalize"))?;
+    let reader = CellReader::parse(instructions)?;
+
+    // Expected labels, either as a whitelist or as a map from label to output key
+    let expected: Option<IndexMap<String, String>> = match instructions.get("labels") {
+        None => None,
+        Some(Value::Array(labels)) => Some(labels.iter()
+            .map(|label| label.as_str()
+                .map(|s| (normalization.apply(s), s.to_string()))
+                .ok_or_else(|| Error::msg("Invalid label in 'labels'")))
+            .collect::<Result<_, Error>>()?),
+        Some(Value::Object(labels)) => Some(labels.iter()
+            .map(|(label, key)| key.as_str()
+                .map(|s| (normalization.apply(label), s.to_string()))
+                .ok_or_else(|| Error::msg("Invalid key in 'labels'")))
+            .collect::<Result<_, Error>>()?),
+        _ => return Err(Error::msg("Invalid 'labels' format")),
+    };
+
+    let mut results = IndexMap::new();
+    // Expected labels are always present in the output, so missing labels show up as null (unless nulls are dropped)
+    if let Some(expected) = &expected {
+        for key in expected.values() {
+            if let Some(value) = reader.apply(Value::Null, &NullPolicy::Keep) {
+                results.insert(key.clone(), value);
+            }
+        }
+    }
+
+    for row in start_row..=end_row {
+        match manipulations::extract_cell_value(sheet, row, label_index, false)? {
+            (Some(label), _) if !label.is_null() => (),
+            _ => continue,
+        }
+        let label = match sheet.get_value((row - 1, label_index)) {
+            Some(Data::String(raw)) => normalization.apply(raw),
+            _ => normalization.apply(&manipulations::extract_cell_value(sheet, row, label_index, true)?.1),
+        };
+        if label.is_empty() {
+            continue;
+        }
+        let value = match reader.apply(reader.read(sheet, row, value_index)?, &NullPolicy::Keep) {
+            Some(value) => value,
+            None => continue,
+        };
+
+        match &expected {
+            Some(expected) => {
+                if let Some(key) = expected.get(&label) {
+                    results.insert(key.clone(), value);
+                }
+            },
+            None => {
+                let mut unique_key = label.clone();
+                let mut counter = 1;
+                while results.contains_key(&unique_key) {
+                    unique_key = format!("{}_{}", label, counter);
+                    counter += 1;
+                }
+                results.insert(unique_key, value);
+            },
+        }
+    }
+    Ok(results)
+}
@@ -2,8 +2,12 @@ use anyhow::{Result, Error};
 use calamine::{Range, Data};
 use serde_json::{Value, Number};
 use crate::utils::conversions;
+use crate::utils::text::TextOptions;
 
 pub fn extract_cell_value(sheet: &Range<Data>, row: u32, col: u32, force_str: bool) -> Result<(Option<Value>, String), Error> {
+    if row == 0 {
+        return Err(Error::msg("Invalid row number 0, rows start at 1"));
+    }
     let cell = sheet.get_value((row-1, col));
     if cell.is_none() {
         return Ok((None, "Null".to_string()));
@@ -56,14 +60,18 @@ pub fn extract_headers(
     sheet: &Range<Data>,
     header_rows: &[u32], // Array of row indices
     column: u32,         // Column index
-    separator: &str      // Separator to join headers
+    separator: &str,     // Separator to join headers
+    text: &TextOptions   // Normalization of the header text
 ) -> Result<String, Error> {
     let mut headers = Vec::new();
 
     for &row in header_rows {
         let (_, header_description) = extract_cell_value(sheet, row, column, true)?; // Force string extraction
-        // Replace carriage returns and new lines with a space
-        let clean_header = header_description.replace("\r\n", " ").replace("\n", " ").replace("\r", " ");
+        // String headers are normalized from the untrimmed text, by default trimmed with line breaks replaced by spaces
+        let clean_header = match sheet.get_value((row - 1, column)) {
+            Some(Data::String(raw)) => text.apply(raw),
+            _ => header_description,
+        };
         headers.push(clean_header);
     }
 
@@ -71,23 +79,12 @@ pub fn extract_headers(
     Ok(headers.join(separator))
 }
 
-pub fn extract_column_data(
-    sheet: &Range<Data>,
-    column: u32,
-    start_row: u32,
-    end_row: u32
-) -> Result<Value, Error> {
-    let mut column_data: Vec<Value> = Vec::new();
-
-    for row in start_row..=end_row {
-        let (cell_value, _) = extract_cell_value(sheet, row, column, false)?;
-        // If cell_value is None, insert null into the column_data
-        let value = match cell_value {
-            Some(v) => v,
-            None => Value::Null,
-        };
-        column_data.push(value);
+/// Text of a value for joining or comparing, with whole numbers written without decimals like the cell text.
+pub fn value_text(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(s) => s.clone(),
+        Value::Number(n) => n.as_f64().map(|f| f.to_string()).unwrap_or_else(|| n.to_string()),
+        other => other.to_string(),
     }
-
-    Ok(Value::Array(column_data))
-}
\ No newline at end of file
+}
@@ -0,0 +1,150 @@
+use anyhow::{Result, Error};
+use calamine::{Range, Data};
+use serde_json::{Map, Value};
+use indexmap::IndexMap;
+use crate::utils::{conversions, manipulations};
+use crate::utils::context::ExtractionContext;
+use crate::utils::reader::{CellReader, NullPolicy};
+use crate::utils::text::TextOptions;
+
+// Appends a counter to the key if needed, like the other extraction functions
+fn unique_key(map: &Map<String, Value>, key: &str) -> String {
+    let mut unique_key = key.to_string();
+    let mut counter = 1;
+    while map.contains_key(&unique_key) {
+        unique_key = format!("{}_{}", key, counter);
+        counter += 1;
+    }
+    unique_key
+}
+
+// Row labels are headers too, so they use the "header_text" options
+fn row_label(sheet: &Range<Data>, row: u32, col: u32, text: &TextOptions) -> Result<Option<String>, Error> {
+    match manipulations::extract_cell_value(sheet, row, col, false)? {
+        (Some(value), _) if !value.is_null() => {
+            let label = manipulations::extract_headers(sheet, &[row], col, "", text)?;
+            Ok(if label.is_empty() { None } else { Some(label) })
+        },
+        _ => Ok(None),
+    }
+}
+
+/// Extracts a 2-D block with row headers down one or more columns and column headers across one or more rows,
+/// e.g. cost categories by year. The output is nested by row and column label, or a list of {row, col, value} records.
+pub fn extract_matrix(sheet: &Range<Data>, instructions: &Map<String, Value>, context: &ExtractionContext) -> Result<IndexMap<String, Value>, Error> {
+    // The value block, as a "range" or as "row_range" and "column_range". Headers are read from the same sheet as the range.
+    let (sheet, (start_row, end_row, start_col, end_col)) = match instructions.get("range") {
+        Some(range) => {
+            let range = range.as_str().ok_or_else(|| Error::msg("Invalid 'range'"))?;
+            let reference = context.resolve_reference(range)?;
+            let source = context.worksheet(sheet, reference.sheet.as_deref());
+            (source, reference.bounds(source))
+        },
+        None => {
+            let (start_row, end_row) = context.row_range(instructions.get("row_range").unwrap_or(&Value::Null))?;
+            let (start_col, end_col) = context.column_range(instructions.get("column_range").unwrap_or(&Value::Null))?;
+            (sheet, (start_row, end_row, start_col, end_col))
+        },
+    };
+
+    // One column per level of row headers, outermost first
+    let row_header_columns: Vec<u32> = match instructions.get("row_headers") {
+        Some(Value::String(column)) => vec![conversions::column_name_to_index(column)?],
+        Some(Value::Array(columns)) if !columns.is_empty() => columns.iter()
+            .map(|column| column.as_str()
+                .ok_or_else(|| Error::msg("Invalid column in 'row_headers'"))
+                .and_then(conversions::column_name_to_index))
+            .collect::<Result<_, Error>>()?,
+        _ => return Err(Error::msg("Missing or invalid 'row_headers'")),
+    };
+
+    let header_indices: Vec<u32> = match instructions.get("header_row") {
+        Some(Value::Number(num)) => vec![num.as_u64().ok_or_else(|| Error::msg("Invalid 'header_row' format"))? as u32],
+        Some(Value::Array(arr)) => arr.iter()
+            .map(|v| v.as_u64().map(|n| n as u32).ok_or_else(|| Error::msg("Invalid 'header_row' format")))
+            .collect::<Result<_, Error>>()?,
+        _ => return Err(Error::msg("Missing or invalid 'header_row'")),
+    };
+    let separator = instructions.get("separator").and_then(Value::as_str).unwrap_or(" ");
+    let reader = CellReader::parse(instructions)?;
+    let long = match instructions.get("output").and_then(Value::as_str).unwrap_or("nested") {
+        "nested" => false,
+        "long" => true,
+        other => return Err(Error::msg(format!("Invalid 'output' option '{}'", other))),
+    };
+
+    let mut column_labels: Vec<(String, u32)> = Vec::new();
+    for col in start_col..=end_col {
+        let header = manipulations::extract_headers(sheet, &header_indices, col, separator, &reader.headers)?;
+        let mut label = header.clone();
+        let mut counter = 1;
+        while column_labels.iter().any(|(existing, _)| *existing == label) {
+            label = format!("{}_{}", header, counter);
+            counter += 1;
+        }
+        column_labels.push((label, col));
+    }
+
+    let mut nested = Map::new();
+    let mut records = Vec::new();
+    // Outer row headers are often only filled on the first row of their group, so they carry down
+    let mut current_labels: Vec<Option<String>> = vec![None; row_header_columns.len()];
+    for row in start_row..=end_row {
+        let mut labels = Vec::new();
+        for (level, col) in row_header_columns.iter().enumerate() {
+            let label = row_label(sheet, row, *col, &reader.headers)?;
+            if label.is_some() || level + 1 == row_header_columns.len() {
+                current_labels[level] = label;
+                // A new outer label starts a new group for the inner levels
+                current_labels[level + 1..].iter_mut().for_each(|inner| *inner = None);
+            }
+            labels.push(current_labels[level].clone());
+        }
+        // Rows without a label of their own (blank or spacer rows) are skipped
+        let labels: Vec<String> = match labels.last() {
+            Some(Some(_)) => labels.into_iter().map(Option::unwrap_or_default).collect(),
+            _ => continue,
+        };
+
+        let mut values = Map::new();
+        for (column_label, col) in &column_labels {
+            if let Some(value) = reader.apply(reader.read(sheet, row, *col)?, &NullPolicy::Keep) {
+                values.insert(column_label.clone(), value);
+            }
+        }
+
+        if long {
+            let row_value = match labels.as_slice() {
+                [label] => Value::String(label.clone()),
+                _ => Value::Array(labels.into_iter().map(Value::String).collect()),
+            };
+            for (column_label, value) in values {
+                let mut record = Map::new();
+                record.insert("row".to_string(), row_value.clone());
+                record.insert("col".to_string(), Value::String(column_label));
+                record.insert("value".to_string(), value);
+                records.push(Value::Object(record));
+            }
+        } else {
+            let mut level_map = &mut nested;
+            let (leaf, outer) = labels.split_last().unwrap();
+            for label in outer {
+                let entry = level_map.entry(label.clone()).or_insert_with(|| Value::Object(Map::new()));
+                if !entry.is_object() {
+                    *entry = Value::Object(Map::new());
+                }
+                level_map = entry.as_object_mut().unwrap();
+            }
+            let key = unique_key(level_map, leaf);
+            level_map.insert(key, Value::Object(values));
+        }
+    }
+
+    if long {
+        let mut results = IndexMap::new();
+        results.insert("data".to_string(), Value::Array(records));
+        Ok(results)
+    } else {
+        Ok(nested.into_iter().collect())
+    }
+}
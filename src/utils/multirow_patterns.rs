@@ -2,77 +2,481 @@ use anyhow::{Result, Error};
 use calamine::{Range, Data};
 use serde_json::{Map, Value};
 use indexmap::IndexMap;
+use std::collections::HashMap;
 use crate::utils::{conversions, manipulations};
+use crate::utils::annotations::Include;
+use crate::utils::context::ExtractionContext;
+use crate::utils::conditions::Check;
+use crate::utils::reader::{CellReader, NullPolicy};
+use crate::utils::styles::StyleCheck;
 
-pub fn extract_rows(sheet: &Range<Data>, instructions: &Map<String, Value>) -> Result<IndexMap<String, Value>, Error> {
-    let mut results = IndexMap::new();
+/// When to stop iterating over the records, checked against the unique_id cell.
+enum StopRule {
+    FirstNull,
+    ConsecutiveNulls(u32),
+    Matches(Check),
+    End,
+}
 
-    // Retrieve and parse the row_range array
-    let row_range = instructions.get("row_range")
-        .and_then(Value::as_array)
-        .ok_or_else(|| Error::msg("Missing or invalid 'row_range'"))?;
-    let start_row = row_range.get(0).and_then(Value::as_u64)
-        .ok_or_else(|| Error::msg("Missing 'start_row' in 'row_range'"))? as u32;  // Adjust for zero-based index
-    let end_row = row_range.get(1).and_then(Value::as_u64)
-        .ok_or_else(|| Error::msg("Missing 'end_row' in 'row_range'"))? as u32;  // Adjust for zero-based index
-
-    let columns = instructions
-        .get("columns")
-        .and_then(Value::as_object)
-        .ok_or_else(|| Error::msg("Missing 'columns'"))?;
-
-    let unique_id_column = instructions
-        .get("unique_id")
-        .and_then(Value::as_str)
-        .ok_or_else(|| Error::msg("Missing 'unique_id'"))?;
-    let unique_id_index = conversions::column_name_to_index(unique_id_column)?;
-
-    for row in start_row..=end_row {
-        let mut row_data = Map::new();
-        match manipulations::extract_cell_value(sheet, row, unique_id_index, false) {
-            Ok((Some(unique_id), _)) if unique_id != Value::Null => {
-                for (column_name, column_index_value) in columns {
-                    let column_values = match column_index_value {
-                        Value::Array(arr) => arr.clone(),
-                        Value::String(s) => vec![Value::String(s.clone())],
-                        _ => return Err(Error::msg("Invalid column specification")),
-                    };
-
-                    let mut cell_values = Vec::new();
-                    for column_index_value in column_values {
-                        let column_index_str = match column_index_value {
-                            Value::String(s) => s,
-                            _ => return Err(Error::msg("Invalid column specification")),
-                        };
-
-                        let col = conversions::column_name_to_index(&column_index_str)?;
-                        match manipulations::extract_cell_value(sheet, row, col, false) {
-                            Ok((Some(value), _)) if !value.is_null() => cell_values.push(value),
-                            Ok((Some(_), _)) => (),  // Handle the case for non-null values that are not needed
-                            Ok((None, _)) => (),     // Ignore when no value is found
-                            Err(e) => return Err(e), // Propagate errors
-                        }
+impl StopRule {
+    fn parse(value: &Value) -> Result<Self, Error> {
+        match value {
+            Value::String(s) if s == "null" => Ok(StopRule::FirstNull),
+            Value::String(s) if s == "end" => Ok(StopRule::End),
+            Value::Object(obj) => {
+                if let Some(count) = obj.get("consecutive_nulls") {
+                    match count.as_u64() {
+                        Some(count) if count > 0 => Ok(StopRule::ConsecutiveNulls(count as u32)),
+                        _ => Err(Error::msg("Invalid 'consecutive_nulls' in 'stop_on'")),
                     }
-
-                    let final_value = match cell_values.len() {
-                        0 => Value::Null,
-                        1 => cell_values.pop().unwrap(),
-                        _ => Value::Array(cell_values),
-                    };
-                    row_data.insert(column_name.clone(), final_value);
+                } else if obj.contains_key("matches") {
+                    Ok(StopRule::Matches(Check::parse(obj)?))
+                } else {
+                    Err(Error::msg("'stop_on' requires 'consecutive_nulls' or 'matches'"))
                 }
+            },
+            _ => Err(Error::msg(format!("Invalid 'stop_on' option {}", value))),
+        }
+    }
 
-                let mut unique_key = unique_id.to_string();
-                let mut counter = 1;
-                while results.contains_key(&unique_key) {
-                    unique_key = format!("{}_{}", unique_id.to_string(), counter);
-                    counter += 1;
+    // Stops at the first null id by default, as documented
+    fn parse_all(value: Option<&Value>) -> Result<Vec<Self>, Error> {
+        match value {
+            None => Ok(vec![StopRule::FirstNull]),
+            Some(Value::Array(rules)) => rules.iter().map(StopRule::parse).collect(),
+            Some(rule) => Ok(vec![StopRule::parse(rule)?]),
+        }
+    }
+}
+
+/// Skips a record when a cell matches, e.g. {"column": "B", "matches": "Subtotal"}, or has a style, e.g. {"strikethrough": true}.
+/// Defaults to the unique_id cell. When both a check and a style are given, both must match.
+struct SkipRule {
+    position: Option<u32>,
+    check: Option<Check>,
+    style: Option<StyleCheck>,
+}
+
+impl SkipRule {
+    fn parse_all(value: Option<&Value>, horizontal: bool) -> Result<Vec<Self>, Error> {
+        let rules = match value {
+            None => return Ok(Vec::new()),
+            Some(Value::Array(rules)) => rules.iter().collect(),
+            Some(rule) => vec![rule],
+        };
+        rules.into_iter()
+            .map(|rule| {
+                let obj = rule.as_object().ok_or_else(|| Error::msg("Each 'skip_when' rule should be a JSON object"))?;
+                // Records run along rows by default, so the position is a column (or a row for the columns orientation)
+                let position = match (horizontal, obj.get("column"), obj.get("row")) {
+                    (false, Some(column), None) => Some(conversions::column_name_to_index(
+                        column.as_str().ok_or_else(|| Error::msg("Invalid 'column' in 'skip_when'"))?)?),
+                    (true, None, Some(row)) => Some(row_number(row).ok_or_else(|| Error::msg("Invalid 'row' in 'skip_when'"))?),
+                    (_, None, None) => None,
+                    _ => return Err(Error::msg("Invalid position in 'skip_when'")),
+                };
+                let style = StyleCheck::parse(obj)?;
+                let check = match style {
+                    Some(_) if !Check::is_given(obj) => None,
+                    _ => Some(Check::parse(obj)?),
+                };
+                Ok(SkipRule { position, check, style })
+            })
+            .collect()
+    }
+}
+
+// Reads a 1-based row number, given as a number or a string such as "3"
+fn row_number(value: &Value) -> Option<u32> {
+    match value {
+        Value::Number(n) => n.as_u64().map(|n| n as u32),
+        Value::String(s) => s.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+/// How the values of a field mapped to several columns (or rows) are combined into one value.
+#[derive(Clone)]
+enum Combine {
+    // A single value when one cell is filled, an array when several are
+    Auto,
+    Array,
+    Join(String),
+    Coalesce,
+    Sum,
+    Positional,
+}
+
+impl Combine {
+    fn parse(options: &Map<String, Value>) -> Result<Self, Error> {
+        let separator = options.get("separator").and_then(Value::as_str).unwrap_or(" ");
+        match options.get("combine").and_then(Value::as_str) {
+            None | Some("auto") => Ok(Combine::Auto),
+            Some("array") => Ok(Combine::Array),
+            Some("join") => Ok(Combine::Join(separator.to_string())),
+            Some("coalesce") => Ok(Combine::Coalesce),
+            Some("sum") => Ok(Combine::Sum),
+            Some("positional") => Ok(Combine::Positional),
+            Some(other) => Err(Error::msg(format!("Invalid 'combine' option '{}'", other))),
+        }
+    }
+
+    /// Takes the (value, text) of each cell in field order, or None for a dropped empty cell.
+    fn apply(&self, cells: Vec<Option<(Value, String)>>) -> Value {
+        if let Combine::Positional = self {
+            return Value::Array(cells.into_iter().map(|cell| cell.map(|(value, _)| value).unwrap_or(Value::Null)).collect());
+        }
+        let mut filled: Vec<(Value, String)> = cells.into_iter().flatten().collect();
+        // With the "keep" null policy, empty cells are kept as nulls in the lists
+        match self {
+            Combine::Auto => match filled.len() {
+                0 => Value::Null,
+                1 => filled.pop().unwrap().0,
+                _ => Value::Array(filled.into_iter().map(|(value, _)| value).collect()),
+            },
+            Combine::Array => Value::Array(filled.into_iter().map(|(value, _)| value).collect()),
+            Combine::Join(separator) if filled.iter().any(|(value, _)| !value.is_null()) => Value::String(filled.into_iter()
+                .filter(|(value, _)| !value.is_null())
+                .map(|(_, text)| text)
+                .collect::<Vec<String>>()
+                .join(separator)),
+            Combine::Coalesce => filled.into_iter().map(|(value, _)| value).find(|value| !value.is_null()).unwrap_or(Value::Null),
+            Combine::Sum => {
+                let numbers: Vec<&Value> = filled.iter().map(|(value, _)| value).filter(|value| value.is_number()).collect();
+                // Integers stay integers, unless one of the values is a float or the sum overflows
+                let integer_sum = numbers.iter()
+                    .try_fold(0i64, |sum, value| value.as_i64().and_then(|number| sum.checked_add(number)));
+                match integer_sum {
+                    _ if numbers.is_empty() => Value::Null,
+                    Some(sum) => Value::from(sum),
+                    None => Value::from(numbers.iter().filter_map(|value| value.as_f64()).sum::<f64>()),
                 }
-                results.insert(unique_key, Value::Object(row_data));
             },
-            Ok(_) => (), // Ignore null or None unique_ids
-            Err(e) => return Err(e),
+            _ => Value::Null,
+        }
+    }
+}
+
+/// A field of the record: its name, the columns (or rows) it reads, and how their values are combined.
+struct Field {
+    name: String,
+    positions: Vec<u32>,
+    combine: Combine,
+}
+
+impl Field {
+    // A field is a column letter (row number for the columns orientation), a list of them,
+    // or an object such as {"columns": ["B", "C"], "combine": "join", "separator": ", "}
+    fn parse(name: &str, spec: &Value, horizontal: bool, default_combine: &Combine) -> Result<Self, Error> {
+        let (positions, combine) = match spec {
+            Value::Object(obj) => {
+                let key = if horizontal { "rows" } else { "columns" };
+                let positions = obj.get(key).ok_or_else(|| Error::msg(format!("Missing '{}' in field '{}'", key, name)))?;
+                let combine = if obj.contains_key("combine") { Combine::parse(obj)? } else { default_combine.clone() };
+                (positions, combine)
+            },
+            other => (other, default_combine.clone()),
+        };
+        let position = |value: &Value| if horizontal {
+            row_number(value).ok_or_else(|| Error::msg("Invalid row specification"))
+        } else {
+            value.as_str()
+                .ok_or_else(|| Error::msg("Invalid column specification"))
+                .and_then(conversions::column_name_to_index)
+        };
+        let positions = match positions {
+            Value::Array(arr) => arr.iter().map(position).collect::<Result<Vec<u32>, Error>>()?,
+            single => vec![position(single)?],
+        };
+        Ok(Field { name: name.to_string(), positions, combine })
+    }
+}
+
+/// What to do when a unique_id repeats. Every repeat is reported as a warning, except with "error".
+enum OnDuplicate {
+    Rename,
+    First,
+    Last,
+    Error,
+}
+
+// Inserts the record under its id path (one key per level), handling repeated ids
+fn insert_record(map: &mut Map<String, Value>, path: &[String], record: Value, on_duplicate: &OnDuplicate, context: &ExtractionContext) -> Result<(), Error> {
+    let (leaf, outer) = path.split_last().ok_or_else(|| Error::msg("Missing 'unique_id'"))?;
+    let mut level = map;
+    for key in outer {
+        level = match level.entry(key.clone()).or_insert_with(|| Value::Object(Map::new())) {
+            Value::Object(inner) => inner,
+            _ => return Err(Error::msg(format!("Unique id '{}' is both a record and a group", key))),
+        };
+    }
+    if !level.contains_key(leaf) {
+        level.insert(leaf.clone(), record);
+        return Ok(());
+    }
+    let id = path.join(" / ");
+    match on_duplicate {
+        OnDuplicate::Rename => {
+            let mut unique_key = leaf.clone();
+            let mut counter = 1;
+            while level.contains_key(&unique_key) {
+                unique_key = format!("{}_{}", leaf, counter);
+                counter += 1;
+            }
+            context.warn(format!("Duplicate unique_id '{}' stored as '{}'", id, unique_key));
+            level.insert(unique_key, record);
+        },
+        OnDuplicate::First => context.warn(format!("Duplicate unique_id '{}' ignored, the first record is kept", id)),
+        OnDuplicate::Last => {
+            context.warn(format!("Duplicate unique_id '{}' replaces the previous record", id));
+            level.insert(leaf.clone(), record);
+        },
+        OnDuplicate::Error => return Err(Error::msg(format!("Duplicate unique_id '{}'", id))),
+    }
+    Ok(())
+}
+
+// Appends the record to the "records" output, handling repeated ids like insert_record. Replaced records are left
+// as None so that the positions in `seen` stay valid, and the list keeps the sheet order.
+fn push_record(records: &mut Vec<Option<Value>>, seen: &mut HashMap<String, usize>, id: String, record: Value, on_duplicate: &OnDuplicate, context: &ExtractionContext) -> Result<(), Error> {
+    let previous = match seen.get(&id) {
+        None => {
+            seen.insert(id, records.len());
+            records.push(Some(record));
+            return Ok(());
+        },
+        Some(previous) => *previous,
+    };
+    match on_duplicate {
+        OnDuplicate::Rename => {
+            context.warn(format!("Duplicate unique_id '{}' kept as a separate record", id));
+            records.push(Some(record));
+        },
+        OnDuplicate::First => context.warn(format!("Duplicate unique_id '{}' ignored, the first record is kept", id)),
+        OnDuplicate::Last => {
+            context.warn(format!("Duplicate unique_id '{}' replaces the previous record", id));
+            records[previous] = None;
+            seen.insert(id, records.len());
+            records.push(Some(record));
+        },
+        OnDuplicate::Error => return Err(Error::msg(format!("Duplicate unique_id '{}'", id))),
+    }
+    Ok(())
+}
+
+/// Extracts one record per row, keyed by the unique_id column. With "orientation": "columns" the layout is transposed:
+/// one record per column, keyed by the unique_id row, with fields mapped to row numbers.
+pub fn extract_rows(sheet: &Range<Data>, instructions: &Map<String, Value>, context: &ExtractionContext) -> Result<IndexMap<String, Value>, Error> {
+    let mut results = IndexMap::new();
+
+    let horizontal = match instructions.get("orientation").and_then(Value::as_str) {
+        None | Some("rows") => false,
+        Some("columns") => true,
+        Some(other) => return Err(Error::msg(format!("Invalid 'orientation' option '{}'", other))),
+    };
+
+    // A list of unique_id columns (or rows) makes a composite id, e.g. Region + Site + Year
+    let unique_id = match instructions.get("unique_id") {
+        Some(Value::Array(parts)) if !parts.is_empty() => parts.clone(),
+        Some(part) if !part.is_array() => vec![part.clone()],
+        _ => return Err(Error::msg("Missing 'unique_id'")),
+    };
+    let reader = CellReader::parse(instructions)?;
+    let include = Include::parse(instructions)?;
+    let annotations = match &include {
+        Some(include) => Some(context.annotations(None, include)?),
+        None => None,
+    };
+    // The default for fields without their own "combine" option
+    let default_combine = Combine::parse(instructions)?;
+    // The records run along the row range (or column range), and the fields are positions across them
+    let (start, end, unique_id_indices, fields) = if horizontal {
+        let (start_col, end_col) = context.column_range(instructions.get("column_range").unwrap_or(&Value::Null))?;
+        let unique_id_rows = unique_id.iter()
+            .map(|part| row_number(part).ok_or_else(|| Error::msg("Invalid 'unique_id' row")))
+            .collect::<Result<Vec<u32>, Error>>()?;
+        let rows = instructions
+            .get("rows")
+            .and_then(Value::as_object)
+            .ok_or_else(|| Error::msg("Missing 'rows'"))?;
+        let fields = rows.iter()
+            .map(|(field_name, spec)| Field::parse(field_name, spec, true, &default_combine))
+            .collect::<Result<Vec<Field>, Error>>()?;
+        (start_col, end_col, unique_id_rows, fields)
+    } else {
+        // Retrieve and parse the row_range, either as an array or a reference such as "5:15"
+        let (start_row, end_row) = context.row_range(instructions.get("row_range").unwrap_or(&Value::Null))?;
+        let columns = instructions
+            .get("columns")
+            .and_then(Value::as_object)
+            .ok_or_else(|| Error::msg("Missing 'columns'"))?;
+        let unique_id_columns = unique_id.iter()
+            .map(|part| part.as_str()
+                .ok_or_else(|| Error::msg("Invalid 'unique_id' column"))
+                .and_then(conversions::column_name_to_index))
+            .collect::<Result<Vec<u32>, Error>>()?;
+        let fields = columns.iter()
+            .map(|(column_name, spec)| Field::parse(column_name, spec, false, &default_combine))
+            .collect::<Result<Vec<Field>, Error>>()?;
+        (start_row, end_row, unique_id_columns, fields)
+    };
+    let unique_id_index = unique_id_indices[0];
+    // Composite ids are joined into one key, or nested with one level per part
+    let nested = match instructions.get("id_format").and_then(Value::as_str) {
+        None | Some("joined") => false,
+        Some("nested") => true,
+        Some(other) => return Err(Error::msg(format!("Invalid 'id_format' option '{}'", other))),
+    };
+    let id_separator = instructions.get("id_separator").and_then(Value::as_str).unwrap_or("|");
+    let on_duplicate = match instructions.get("on_duplicate").and_then(Value::as_str) {
+        None | Some("rename") => OnDuplicate::Rename,
+        Some("first") => OnDuplicate::First,
+        Some("last") => OnDuplicate::Last,
+        Some("error") => OnDuplicate::Error,
+        Some(other) => return Err(Error::msg(format!("Invalid 'on_duplicate' option '{}'", other))),
+    };
+    let mut keyed = Map::new();
+    // "records" returns an ordered list with the source position of each record, instead of a map keyed by unique_id
+    let as_records = match instructions.get("output").and_then(Value::as_str) {
+        None | Some("map") => false,
+        Some("records") => true,
+        Some(other) => return Err(Error::msg(format!("Invalid 'output' option '{}'", other))),
+    };
+    let mut records = Vec::new();
+    let mut seen = HashMap::new();
+
+    let stop_rules = StopRule::parse_all(instructions.get("stop_on"))?;
+    let skip_rules = SkipRule::parse_all(instructions.get("skip_when"), horizontal)?;
+    let styles = match skip_rules.iter().any(|rule| rule.style.is_some()) {
+        true => context.styles(None)?,
+        false => None,
+    };
+    // Maps a record index and a field position to the (row, col) of the cell
+    let cell = |record: u32, position: u32| if horizontal { (position, record) } else { (record, position) };
+
+    let mut consecutive_nulls = 0;
+    for record in start..=end {
+        // The text of each id part, or None for an empty cell
+        let mut id_parts = Vec::new();
+        for position in &unique_id_indices {
+            let (row, col) = cell(record, *position);
+            match manipulations::extract_cell_value(sheet, row, col, false)? {
+                // String ids are not wrapped in JSON quotes, other ids keep their JSON text (e.g. "2024.0")
+                (Some(Value::String(text)), _) => id_parts.push(Some(text)),
+                (Some(value), _) if !value.is_null() => id_parts.push(Some(value.to_string())),
+                _ => id_parts.push(None),
+            }
+        }
+        if id_parts.iter().all(Option::is_none) {
+            consecutive_nulls += 1;
+            let stop = stop_rules.iter().any(|rule| match rule {
+                StopRule::FirstNull => true,
+                StopRule::ConsecutiveNulls(count) => consecutive_nulls >= *count,
+                StopRule::Matches(_) | StopRule::End => false,
+            });
+            if stop {
+                break;
+            }
+            continue;
+        }
+        consecutive_nulls = 0;
+
+        let (id_row, id_col) = cell(record, unique_id_index);
+        let (unique_id, _) = manipulations::extract_cell_value(sheet, id_row, id_col, false)?;
+        let unique_id = unique_id.unwrap_or(Value::Null);
+        if stop_rules.iter().any(|rule| matches!(rule, StopRule::Matches(check) if check.is_met(&unique_id))) {
+            break;
+        }
+        let mut skip = false;
+        for rule in &skip_rules {
+            let (row, col) = cell(record, rule.position.unwrap_or(unique_id_index));
+            let (value, _) = manipulations::extract_cell_value(sheet, row, col, false)?;
+            let check_met = rule.check.as_ref().is_none_or(|check| check.is_met(&value.unwrap_or(Value::Null)));
+            let style_met = match (&rule.style, &styles) {
+                (Some(style), Some(styles)) => style.is_met(styles.style((row, col))),
+                // Styles are only read from xlsx files, elsewhere a style rule never skips
+                (Some(_), None) => false,
+                (None, _) => true,
+            };
+            if check_met && style_met {
+                skip = true;
+                break;
+            }
+        }
+        if skip {
+            continue;
+        }
+
+        let mut row_data = Map::new();
+        for field in &fields {
+            let mut cell_values = Vec::new();
+            for position in &field.positions {
+                let (row, col) = cell(record, *position);
+                // Empty cells are dropped from the combined values by default
+                let value = reader.apply(reader.read(sheet, row, col)?, &NullPolicy::Drop);
+                cell_values.push(value.map(|value| {
+                    let text = manipulations::value_text(&value);
+                    (value, text)
+                }));
+            }
+            let value = reader.apply(field.combine.apply(cell_values), &NullPolicy::Keep);
+            // Annotations and data validations apply to the fields that read a single cell
+            let value = match (&include, &annotations, field.positions.as_slice()) {
+                (Some(include), Some(annotations), [position]) => annotations.annotate(value, cell(record, *position), include, context),
+                _ => value,
+            };
+            if let Some(value) = value {
+                row_data.insert(field.name.clone(), value);
+            }
+        }
+
+        let id_parts: Vec<String> = id_parts.into_iter().map(Option::unwrap_or_default).collect();
+        let path = if nested { id_parts } else { vec![id_parts.join(id_separator)] };
+        if as_records {
+            if horizontal {
+                row_data.insert("_column".to_string(), Value::String(conversions::column_index_to_name(record)));
+            } else {
+                row_data.insert("_row".to_string(), Value::from(record));
+            }
+            row_data.insert("_sheet".to_string(), Value::String(context.sheet_name.to_string()));
+            push_record(&mut records, &mut seen, path.join(" / "), Value::Object(row_data), &on_duplicate, context)?;
+            continue;
         }
+        insert_record(&mut keyed, &path, Value::Object(row_data), &on_duplicate, context)?;
+    }
+    results.extend(keyed);
+    if as_records {
+        results.insert("data".to_string(), Value::Array(records.into_iter().flatten().collect()));
     }
     Ok(results)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::cell::RefCell;
+
+    #[test]
+    fn id_keys_keep_numbers_as_json_and_strings_unquoted() {
+        let mut sheet = Range::new((0, 0), (1, 1));
+        sheet.set_value((0, 0), Data::String("Well A".to_string()));
+        sheet.set_value((0, 1), Data::Float(120.0));
+        sheet.set_value((1, 0), Data::Float(2024.0));
+        sheet.set_value((1, 1), Data::Float(95.0));
+        let sheets = HashMap::new();
+        let context = ExtractionContext {
+            sheet_name: "Sheet1",
+            defined_names: &[],
+            tables: &[],
+            sheets: &sheets,
+            parts: None,
+            warnings: RefCell::new(Vec::new()),
+        };
+        let instructions = json!({"row_range": [1, 2], "unique_id": "A", "columns": {"Depth": "B"}});
+        let results = extract_rows(&sheet, instructions.as_object().unwrap(), &context).unwrap();
+        let keys: Vec<&str> = results.keys().map(String::as_str).collect();
+        assert_eq!(keys, ["Well A", "2024.0"]);
+        assert_eq!(results["2024.0"], json!({"Depth": 95.0}));
+    }
+}
@@ -0,0 +1,69 @@
+use anyhow::{Result, Error};
+use calamine::{Range, Data};
+use serde_json::{Map, Value};
+use crate::utils::manipulations;
+use crate::utils::text::TextOptions;
+
+/// What to do with an empty cell.
+#[derive(Clone)]
+pub enum NullPolicy {
+    Keep,
+    Drop,
+    Default(Value),
+}
+
+/// The cell reading options shared by the extraction functions, read from their instructions:
+/// null handling and text normalization. Without a "nulls" option each function keeps its own default policy.
+pub struct CellReader {
+    policy: Option<NullPolicy>,
+    out_of_range: Option<Value>,
+    values: TextOptions,
+    pub headers: TextOptions,
+}
+
+impl CellReader {
+    pub fn parse(instructions: &Map<String, Value>) -> Result<Self, Error> {
+        let policy = match instructions.get("nulls") {
+            None => None,
+            Some(Value::String(s)) if s == "keep" => Some(NullPolicy::Keep),
+            Some(Value::String(s)) if s == "drop" => Some(NullPolicy::Drop),
+            Some(Value::Object(obj)) if obj.contains_key("default") => Some(NullPolicy::Default(obj["default"].clone())),
+            Some(other) => return Err(Error::msg(format!("Invalid 'nulls' option {}", other))),
+        };
+        // Cells outside the used range of the sheet are read as null unless a marker value is given
+        let out_of_range = instructions.get("out_of_range").cloned();
+        let values = TextOptions::parse(instructions.get("value_text"), TextOptions::values())?;
+        let headers = TextOptions::parse(instructions.get("header_text"), TextOptions::headers())?;
+        Ok(CellReader { policy, out_of_range, values, headers })
+    }
+
+    /// The policy to apply, falling back to the default of the calling function.
+    pub fn policy<'a>(&'a self, fallback: &'a NullPolicy) -> &'a NullPolicy {
+        self.policy.as_ref().unwrap_or(fallback)
+    }
+
+    /// Reads a cell as a value, where an empty cell is null and a cell outside the used range is the "out_of_range" marker.
+    /// String cells are normalized with the "value_text" options.
+    pub fn read(&self, sheet: &Range<Data>, row: u32, col: u32) -> Result<Value, Error> {
+        match manipulations::extract_cell_value(sheet, row, col, false)? {
+            (Some(value), _) => match sheet.get_value((row - 1, col)) {
+                // The untrimmed text, since trimming is one of the options
+                Some(Data::String(text)) => Ok(Value::String(self.values.apply(text))),
+                _ => Ok(value),
+            },
+            (None, _) => Ok(self.out_of_range.clone().unwrap_or(Value::Null)),
+        }
+    }
+
+    /// Applies the policy to a value: None means the value is dropped.
+    pub fn apply(&self, value: Value, fallback: &NullPolicy) -> Option<Value> {
+        if !value.is_null() {
+            return Some(value);
+        }
+        match self.policy(fallback) {
+            NullPolicy::Keep => Some(Value::Null),
+            NullPolicy::Drop => None,
+            NullPolicy::Default(default) => Some(default.clone()),
+        }
+    }
+}
@@ -0,0 +1,176 @@
+use anyhow::{Result, Error};
+use calamine::{Range, Data};
+use serde_json::{Map, Value};
+use indexmap::IndexMap;
+use regex::Regex;
+use crate::utils::{conversions, manipulations};
+use crate::utils::context::ExtractionContext;
+use crate::utils::reader::{CellReader, NullPolicy};
+
+/// How consecutive blocks are found below the first one.
+enum Separator {
+    Height(u32),
+    Blank,
+    Marker(Regex),
+}
+
+/// A field of the block template, as (row offset, column offset) pairs relative to the block start.
+struct Field {
+    name: String,
+    offsets: Vec<(u32, u32)>,
+}
+
+fn parse_fields(template: &Map<String, Value>) -> Result<Vec<Field>, Error> {
+    let parse_offset = |address: &Value| -> Result<(u32, u32), Error> {
+        let address = address.as_str().ok_or_else(|| Error::msg("Invalid address in 'fields'"))?;
+        let reference = conversions::parse_reference(address)?;
+        match (&reference.sheet, reference.cell()) {
+            (None, Some((row, col))) => Ok((row - 1, col)),
+            _ => Err(Error::msg(format!("Invalid relative address '{}' in 'fields'", address))),
+        }
+    };
+    template.iter()
+        .map(|(name, addresses)| {
+            let offsets = match addresses {
+                Value::Array(arr) => arr.iter().map(parse_offset).collect::<Result<_, Error>>()?,
+                address => vec![parse_offset(address)?],
+            };
+            Ok(Field { name: name.clone(), offsets })
+        })
+        .collect()
+}
+
+fn is_blank_row(sheet: &Range<Data>, row: u32, (start_col, end_col): (u32, u32)) -> Result<bool, Error> {
+    for col in start_col..=end_col {
+        if let (Some(value), _) = manipulations::extract_cell_value(sheet, row, col, false)? {
+            if !value.is_null() && value != Value::String(String::new()) {
+                return Ok(false);
+            }
+        }
+    }
+    Ok(true)
+}
+
+// Returns the 1-based start row of every block, up to the last used row of the sheet
+fn block_starts(sheet: &Range<Data>, separator: &Separator, (start_row, start_col): (u32, u32), columns: (u32, u32)) -> Result<Vec<u32>, Error> {
+    let last_row = sheet.end().map(|(row, _)| row + 1).unwrap_or(0);
+    let mut starts = Vec::new();
+    match separator {
+        Separator::Height(height) => {
+            let mut row = start_row;
+            while row <= last_row {
+                starts.push(row);
+                row += height;
+            }
+        },
+        Separator::Blank => {
+            let mut previous_blank = true;
+            for row in start_row..=last_row {
+                let blank = is_blank_row(sheet, row, columns)?;
+                if previous_blank && !blank {
+                    starts.push(row);
+                }
+                previous_blank = blank;
+            }
+        },
+        Separator::Marker(regex) => {
+            for row in start_row..=last_row {
+                if let (Some(Value::String(text)), _) = manipulations::extract_cell_value(sheet, row, start_col, false)? {
+                    if regex.is_match(&text) {
+                        starts.push(row);
+                    }
+                }
+            }
+        },
+    }
+    Ok(starts)
+}
+
+/// Extracts records that span several rows, repeated down the sheet, e.g. inspection forms with a 6-row block per inspection.
+/// The field addresses are relative to the top-left cell of each block, so "A1" is the block start.
+pub fn extract_blocks(sheet: &Range<Data>, instructions: &Map<String, Value>, context: &ExtractionContext) -> Result<IndexMap<String, Value>, Error> {
+    let start = instructions.get("start")
+        .and_then(Value::as_str)
+        .ok_or_else(|| Error::msg("Missing 'start'"))?;
+    let (sheet_name, start_row, start_col) = context.resolve_cell(start)?;
+    let sheet = context.worksheet(sheet, sheet_name.as_deref());
+
+    let fields = parse_fields(instructions.get("fields")
+        .and_then(Value::as_object)
+        .ok_or_else(|| Error::msg("Missing 'fields'"))?)?;
+    if fields.is_empty() {
+        return Err(Error::msg("'fields' should not be empty"));
+    }
+
+    let separator = match (instructions.get("height"), instructions.get("separator")) {
+        (Some(height), None) => match height.as_u64() {
+            Some(height) if height > 0 => Separator::Height(height as u32),
+            _ => return Err(Error::msg("Invalid 'height'")),
+        },
+        (None, Some(Value::String(separator))) if separator == "blank" => Separator::Blank,
+        (None, Some(Value::Object(separator))) => {
+            let pattern = separator.get("matches")
+                .and_then(Value::as_str)
+                .ok_or_else(|| Error::msg("Invalid 'matches' pattern in 'separator'"))?;
+            Separator::Marker(Regex::new(pattern)?)
+        },
+        (None, None) => return Err(Error::msg("Missing 'height' or 'separator'")),
+        _ => return Err(Error::msg("Invalid 'height' or 'separator'")),
+    };
+    let max_blocks = instructions.get("max_blocks").and_then(Value::as_u64).map(|n| n as usize);
+    let reader = CellReader::parse(instructions)?;
+
+    // Blank rows are checked across the columns used by the fields
+    let offsets = fields.iter().flat_map(|field| field.offsets.iter());
+    let columns = (
+        start_col + offsets.clone().map(|(_, col)| *col).min().unwrap_or(0),
+        start_col + offsets.map(|(_, col)| *col).max().unwrap_or(0),
+    );
+
+    let mut records = Vec::new();
+    for block_start in block_starts(sheet, &separator, (start_row, start_col), columns)? {
+        if max_blocks.is_some_and(|max_blocks| records.len() >= max_blocks) {
+            break;
+        }
+        let mut record = Map::new();
+        let mut empty = true;
+        for field in &fields {
+            let mut cell_values = Vec::new();
+            for (row_offset, col_offset) in &field.offsets {
+                let (row, col) = (block_start + row_offset, start_col + col_offset);
+                // Cells outside the used range count as empty, whatever their "out_of_range" marker
+                if let (Some(raw), _) = manipulations::extract_cell_value(sheet, row, col, false)? {
+                    if !raw.is_null() && raw != Value::String(String::new()) {
+                        empty = false;
+                    }
+                }
+                let value = reader.read(sheet, row, col)?;
+                // A field with several addresses drops empty cells by default, like multirow_patterns
+                if field.offsets.len() > 1 {
+                    if let Some(value) = reader.apply(value, &NullPolicy::Drop) {
+                        cell_values.push(value);
+                    }
+                } else {
+                    cell_values.push(value);
+                }
+            }
+            let value = match cell_values.len() {
+                0 => Value::Null,
+                1 => cell_values.pop().unwrap(),
+                _ => Value::Array(cell_values),
+            };
+            if let Some(value) = reader.apply(value, &NullPolicy::Keep) {
+                record.insert(field.name.clone(), value);
+            }
+        }
+        // The first block without any value ends the repetition
+        if empty {
+            break;
+        }
+        records.push(Value::Object(record));
+    }
+
+    let mut results = IndexMap::new();
+    results.insert("data".to_string(), Value::Array(records));
+    Ok(results)
+}
@@ -2,42 +2,81 @@ use anyhow::{Result, Error};
 use calamine::{Range, Data};
 use serde_json::{Map, Value};
 use indexmap::IndexMap;
-use crate::utils::{conversions, manipulations};
+use std::collections::HashMap;
+use crate::utils::annotations::{Include, SheetAnnotations};
+use crate::utils::context::ExtractionContext;
+use crate::utils::reader::{CellReader, NullPolicy};
 
-pub fn extract_values(sheet: &Range<Data>, instructions: &Map<String, Value>) -> Result<IndexMap<String, Value>, Error> {
+// Reading options are grouped under this key, so any other key can name an output field
+const OPTIONS: &str = "options";
+
+/// Single values keep nulls by default, while lists of addresses or ranges drop them.
+/// With "include" or "validation" in "options", each value comes with the comment, hyperlink, rich text or data validation of its cell.
+pub fn extract_values(sheet: &Range<Data>, instructions: &Map<String, Value>, context: &ExtractionContext) -> Result<IndexMap<String, Value>, Error> {
+    let options = match instructions.get(OPTIONS) {
+        Some(Value::Object(options)) => options.clone(),
+        Some(_) => return Err(Error::msg("Invalid 'options', expected a dictionary")),
+        None => Map::new(),
+    };
+    let reader = CellReader::parse(&options)?;
+    let include = Include::parse(&options)?;
+    // Annotations are read once for each sheet the addresses refer to
+    let mut annotations: HashMap<String, SheetAnnotations> = HashMap::new();
+    let mut read = |source: &Range<Data>, sheet_name: Option<&str>, row: u32, col: u32, fallback: &NullPolicy| -> Result<Option<Value>, Error> {
+        let value = reader.apply(reader.read(source, row, col)?, fallback);
+        let Some(include) = &include else { return Ok(value) };
+        let sheet_name = sheet_name.unwrap_or(context.sheet_name);
+        if !annotations.contains_key(sheet_name) {
+            annotations.insert(sheet_name.to_string(), context.annotations(Some(sheet_name), include)?);
+        }
+        Ok(annotations[sheet_name].annotate(value, (row, col), include, context))
+    };
     let mut results = IndexMap::new();
     for (key, value) in instructions {
+        if key == OPTIONS {
+            continue;
+        }
         match value {
             Value::Array(addresses) => {
                 let mut address_values = Vec::new();
                 for address_value in addresses {
-                    let (row, col) = match address_value {
-                        Value::String(cell_address) => conversions::address_to_row_col(&cell_address)?,
+                    let (sheet_name, row, col) = match address_value {
+                        Value::String(cell_address) => context.resolve_cell(cell_address)?,
                         Value::Object(obj) => {
                             let row = obj.get("row").and_then(Value::as_u64).ok_or_else(|| Error::msg("Missing 'row'"))? as u32;
                             let col = obj.get("col").and_then(Value::as_u64).ok_or_else(|| Error::msg("Missing 'col'"))? as u32;
-                            (row, col)
+                            let sheet_name = obj.get("sheet").and_then(Value::as_str).map(str::to_string);
+                            (sheet_name, row, col)
                         }
                         _ => return Err(Error::msg("Invalid or missing row/column specification")),
                     };
-                    match manipulations::extract_cell_value(sheet, row, col, false) {
-                        Ok((Some(cell_value), _)) => {
-                            if !cell_value.is_null() {
-                                address_values.push(cell_value);
-                            }
-                        }
-                        Ok((None, _)) => (), // Ignore null values
-                        Err(e) => return Err(e),
+                    let source = context.worksheet(sheet, sheet_name.as_deref());
+                    if let Some(cell_value) = read(source, sheet_name.as_deref(), row, col, &NullPolicy::Drop)? {
+                        address_values.push(cell_value);
                     }
                 }
                 results.insert(key.clone(), Value::Array(address_values));
             }
             Value::String(cell_address) => {
-                let (row, col) = conversions::address_to_row_col(&cell_address)?;
-                match manipulations::extract_cell_value(sheet, row, col, false) {
-                    Ok((Some(cell_value), _)) => { results.insert(key.clone(), cell_value); }
-                    Ok((None, _)) => { results.insert(key.clone(), Value::Null); }
-                    Err(e) => return Err(e),
+                let reference = context.resolve_reference(cell_address)?;
+                // Sheet-qualified references such as "Cover!B3" read from another sheet of the workbook
+                let source = context.worksheet(sheet, reference.sheet.as_deref());
+                if let Some((row, col)) = reference.cell() {
+                    if let Some(cell_value) = read(source, reference.sheet.as_deref(), row, col, &NullPolicy::Keep)? {
+                        results.insert(key.clone(), cell_value);
+                    }
+                } else {
+                    // A range (e.g. "B5:B9") is read row by row into an array, like a list of addresses
+                    let (start_row, end_row, start_col, end_col) = reference.bounds(source);
+                    let mut address_values = Vec::new();
+                    for row in start_row..=end_row {
+                        for col in start_col..=end_col {
+                            if let Some(cell_value) = read(source, reference.sheet.as_deref(), row, col, &NullPolicy::Drop)? {
+                                address_values.push(cell_value);
+                            }
+                        }
+                    }
+                    results.insert(key.clone(), Value::Array(address_values));
                 }
             }
             _ => return Err(Error::msg("Invalid or missing row/column specification")),
@@ -0,0 +1,486 @@
+use anyhow::{Result, Error};
+use calamine::{Range, Data};
+use serde_json::{Map, Value};
+use indexmap::IndexMap;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use std::collections::HashMap;
+use std::rc::Rc;
+use crate::utils::conversions;
+use crate::utils::context::ExtractionContext;
+use crate::utils::reader::CellReader;
+use crate::utils::xlsx_parts::{self, flag, Element, XlsxParts};
+
+/// The formatting of a cell that extractions can filter on or report. Colors are "RRGGBB" hex strings.
+#[derive(Default, Clone, PartialEq)]
+pub struct CellStyle {
+    pub bold: bool,
+    pub italic: bool,
+    pub underline: bool,
+    pub strikethrough: bool,
+    pub font_color: Option<String>,
+    pub fill_color: Option<String>,
+}
+
+impl CellStyle {
+    pub fn to_value(&self) -> Value {
+        let mut map = Map::new();
+        map.insert("bold".to_string(), Value::Bool(self.bold));
+        map.insert("italic".to_string(), Value::Bool(self.italic));
+        map.insert("underline".to_string(), Value::Bool(self.underline));
+        map.insert("strikethrough".to_string(), Value::Bool(self.strikethrough));
+        map.insert("font_color".to_string(), self.font_color.clone().map_or(Value::Null, Value::String));
+        map.insert("fill_color".to_string(), self.fill_color.clone().map_or(Value::Null, Value::String));
+        Value::Object(map)
+    }
+}
+
+// The default palette of indexed colors, used unless the workbook redefines it
+const INDEXED_COLORS: [&str; 64] = [
+    "000000", "FFFFFF", "FF0000", "00FF00", "0000FF", "FFFF00", "FF00FF", "00FFFF",
+    "000000", "FFFFFF", "FF0000", "00FF00", "0000FF", "FFFF00", "FF00FF", "00FFFF",
+    "800000", "008000", "000080", "808000", "800080", "008080", "C0C0C0", "808080",
+    "9999FF", "993366", "FFFFCC", "CCFFFF", "660066", "FF8080", "0066CC", "CCCCFF",
+    "000080", "FF00FF", "FFFF00", "00FFFF", "800080", "800000", "008080", "0000FF",
+    "00CCFF", "CCFFFF", "CCFFCC", "FFFF99", "99CCFF", "FF99CC", "CC99FF", "FFCC99",
+    "3366FF", "33CCCC", "99CC00", "FFCC00", "FF9900", "FF6600", "666699", "969696",
+    "003366", "339966", "003300", "333300", "993300", "993366", "333399", "333333",
+];
+
+// An "AARRGGBB" or "RRGGBB" color as "RRGGBB"
+fn rgb(color: &str) -> Option<String> {
+    let color = color.trim().trim_start_matches('#').to_uppercase();
+    let color = if color.len() == 8 { color[2..].to_string() } else { color };
+    (color.len() == 6 && color.chars().all(|c| c.is_ascii_hexdigit())).then_some(color)
+}
+
+// Lightens (positive tint) or darkens (negative tint) a color through its luminance, as Excel does for theme colors
+fn apply_tint(color: &str, tint: f64) -> String {
+    let channel = |index: usize| u8::from_str_radix(&color[index..index + 2], 16).unwrap_or(0) as f64 / 255.0;
+    let (r, g, b) = (channel(0), channel(2), channel(4));
+    let (max, min) = (r.max(g).max(b), r.min(g).min(b));
+    let lum = (max + min) / 2.0;
+    let (hue, sat) = if max == min {
+        (0.0, 0.0)
+    } else {
+        let delta = max - min;
+        let sat = if lum < 0.5 { delta / (max + min) } else { delta / (2.0 - max - min) };
+        let hue = if max == r {
+            (g - b) / delta + if g < b { 6.0 } else { 0.0 }
+        } else if max == g {
+            (b - r) / delta + 2.0
+        } else {
+            (r - g) / delta + 4.0
+        };
+        (hue / 6.0, sat)
+    };
+    let lum = if tint < 0.0 { lum * (1.0 + tint) } else { lum * (1.0 - tint) + tint };
+    let (r, g, b) = if sat == 0.0 {
+        (lum, lum, lum)
+    } else {
+        let q = if lum < 0.5 { lum * (1.0 + sat) } else { lum + sat - lum * sat };
+        let p = 2.0 * lum - q;
+        let component = |t: f64| {
+            let t = if t < 0.0 { t + 1.0 } else if t > 1.0 { t - 1.0 } else { t };
+            if t < 1.0 / 6.0 {
+                p + (q - p) * 6.0 * t
+            } else if t < 0.5 {
+                q
+            } else if t < 2.0 / 3.0 {
+                p + (q - p) * (2.0 / 3.0 - t) * 6.0
+            } else {
+                p
+            }
+        };
+        (component(hue + 1.0 / 3.0), component(hue), component(hue - 1.0 / 3.0))
+    };
+    format!("{:02X}{:02X}{:02X}", (r * 255.0).round() as u8, (g * 255.0).round() as u8, (b * 255.0).round() as u8)
+}
+
+/// The colors a style refers to by number: the indexed palette and the colors of the workbook theme.
+struct Palette {
+    indexed: Vec<String>,
+    theme: Vec<String>,
+}
+
+impl Palette {
+    fn load(parts: &XlsxParts, stylesheet: &Element) -> Result<Self, Error> {
+        let indexed = match stylesheet.child("colors").and_then(|colors| colors.child("indexedColors")) {
+            Some(colors) => colors.children("rgbColor").filter_map(|color| color.attr("rgb").and_then(rgb)).collect(),
+            None => INDEXED_COLORS.iter().map(|color| color.to_string()).collect(),
+        };
+        let mut theme = Vec::new();
+        if let Some(path) = parts.workbook_part("theme")? {
+            let scheme = parts.xml(&path)?;
+            if let Some(scheme) = scheme.child("themeElements").and_then(|elements| elements.child("clrScheme")) {
+                for color in &scheme.children {
+                    let value = color.child("srgbClr").and_then(|srgb| srgb.attr("val"))
+                        .or_else(|| color.child("sysClr").and_then(|system| system.attr("lastClr")));
+                    theme.push(value.and_then(rgb).unwrap_or_default());
+                }
+            }
+            // The scheme lists dark 1, light 1, dark 2, light 2, while styles number them light first
+            if theme.len() >= 4 {
+                theme.swap(0, 1);
+                theme.swap(2, 3);
+            }
+        }
+        Ok(Palette { indexed, theme })
+    }
+
+    // A <color>, <fgColor>... element, given as "rgb", "indexed" or "theme" (with an optional "tint"). None for automatic colors.
+    fn color(&self, element: &Element) -> Option<String> {
+        if let Some(color) = element.attr("rgb") {
+            return rgb(color);
+        }
+        if let Some(index) = element.attr("indexed").and_then(|index| index.parse::<usize>().ok()) {
+            return self.indexed.get(index).cloned();
+        }
+        let color = element.attr("theme")
+            .and_then(|index| index.parse::<usize>().ok())
+            .and_then(|index| self.theme.get(index))
+            .filter(|color| !color.is_empty())?;
+        match element.attr("tint").and_then(|tint| tint.parse::<f64>().ok()) {
+            Some(tint) if tint != 0.0 => Some(apply_tint(color, tint)),
+            _ => Some(color.clone()),
+        }
+    }
+}
+
+/// The cell formats of the workbook, in the order cells refer to them by their "s" attribute.
+/// There is always at least the default format. Use `XlsxParts::cell_formats` to read them once per workbook.
+pub fn load_formats(parts: &XlsxParts) -> Result<Vec<CellStyle>, Error> {
+    let Some(path) = parts.workbook_part("styles")? else { return Ok(vec![CellStyle::default()]) };
+    let stylesheet = parts.xml(&path)?;
+    let palette = Palette::load(parts, &stylesheet)?;
+    let list = |name: &str, item: &'static str| -> Vec<&Element> {
+        stylesheet.child(name).map(|list| list.children(item).collect()).unwrap_or_default()
+    };
+    let fonts = list("fonts", "font");
+    let fills: Vec<Option<String>> = list("fills", "fill")
+        .into_iter()
+        .map(|fill| {
+            let pattern = fill.child("patternFill")?;
+            match pattern.attr("patternType") {
+                None | Some("none") => None,
+                Some(_) => pattern.child("fgColor").and_then(|color| palette.color(color)),
+            }
+        })
+        .collect();
+    let mut formats: Vec<CellStyle> = list("cellXfs", "xf")
+        .into_iter()
+        .map(|format| {
+            let mut style = CellStyle::default();
+            let id = |name: &str| format.attr(name).and_then(|id| id.parse::<usize>().ok()).unwrap_or(0);
+            if let Some(font) = fonts.get(id("fontId")) {
+                style.bold = font.child("b").is_some_and(flag);
+                style.italic = font.child("i").is_some_and(flag);
+                style.strikethrough = font.child("strike").is_some_and(flag);
+                style.underline = font.child("u").is_some_and(|underline| underline.attr("val") != Some("none"));
+                style.font_color = font.child("color").and_then(|color| palette.color(color));
+            }
+            style.fill_color = fills.get(id("fillId")).cloned().flatten();
+            style
+        })
+        .collect();
+    if formats.is_empty() {
+        formats.push(CellStyle::default());
+    }
+    Ok(formats)
+}
+
+// An attribute of a streamed element, by local name
+fn attribute(element: &BytesStart, name: &str) -> Option<String> {
+    element.attributes().with_checks(false)
+        .filter_map(Result::ok)
+        .find(|attribute| attribute.key.local_name().as_ref() == name.as_bytes())
+        .map(|attribute| String::from_utf8_lossy(&attribute.value).into_owned())
+}
+
+fn number(element: &BytesStart, name: &str) -> Option<usize> {
+    attribute(element, name).and_then(|number| number.parse().ok())
+}
+
+/// The style of each cell of a sheet, from its own format, or the format of its row or column when it has no cell element.
+pub struct SheetStyles {
+    // Shared by the sheets of the workbook
+    formats: Rc<Vec<CellStyle>>,
+    // Format index of each cell element, in sheet order
+    cells: IndexMap<(u32, u32), usize>,
+    rows: HashMap<u32, usize>,
+    // (first column, last column, format index), with 0-based columns
+    columns: Vec<(u32, u32, usize)>,
+}
+
+impl SheetStyles {
+    pub fn load(parts: &XlsxParts, sheet_name: &str) -> Result<Self, Error> {
+        let formats = parts.cell_formats()?;
+        // Only the style indices are needed, so the cells are streamed instead of parsed into a tree
+        let mut reader = Reader::from_str(parts.part(parts.sheet_part(sheet_name)?)?);
+        let mut columns = Vec::new();
+        let mut rows = HashMap::new();
+        let mut cells = IndexMap::new();
+        let (mut row_number, mut col) = (0, 0);
+        loop {
+            let element = match reader.read_event()? {
+                Event::Start(element) | Event::Empty(element) => element,
+                Event::Eof => break,
+                _ => continue,
+            };
+            match element.local_name().as_ref() {
+                b"col" => {
+                    let bound = |name: &str| number(&element, name).filter(|number| *number > 0);
+                    if let (Some(min), Some(max), Some(format)) = (bound("min"), bound("max"), number(&element, "style")) {
+                        columns.push((min as u32 - 1, max as u32 - 1, format));
+                    }
+                },
+                b"row" => {
+                    row_number = number(&element, "r").map_or(row_number + 1, |number| number as u32);
+                    col = 0;
+                    // The format of a row only applies when it is marked as custom
+                    if let (Some(format), true) = (number(&element, "s"), matches!(attribute(&element, "customFormat").as_deref(), Some("1") | Some("true"))) {
+                        rows.insert(row_number, format);
+                    }
+                },
+                b"c" => {
+                    // The cell reference is optional, cells without one follow the previous cell
+                    let position = match attribute(&element, "r") {
+                        Some(address) => xlsx_parts::cell_position(&address)?,
+                        None => (row_number, col),
+                    };
+                    col = position.1 + 1;
+                    cells.insert(position, number(&element, "s").unwrap_or(0));
+                },
+                _ => (),
+            }
+        }
+        Ok(SheetStyles { formats, cells, rows, columns })
+    }
+
+    pub fn style(&self, (row, col): (u32, u32)) -> &CellStyle {
+        let format = self.cells.get(&(row, col)).copied()
+            .or_else(|| self.rows.get(&row).copied())
+            .or_else(|| self.columns.iter().find(|(min, max, _)| (*min..=*max).contains(&col)).map(|(_, _, format)| *format))
+            .unwrap_or(0);
+        self.formats.get(format).unwrap_or(&self.formats[0])
+    }
+
+    /// Whether the style of the cell differs from the default style of the workbook.
+    pub fn is_styled(&self, position: (u32, u32)) -> bool {
+        *self.style(position) != self.formats[0]
+    }
+
+    /// The positions of the cell elements of the sheet, which include formatted empty cells.
+    pub fn cells(&self) -> impl Iterator<Item = &(u32, u32)> {
+        self.cells.keys()
+    }
+}
+
+/// A check on a color: a given color, or whether there is a color at all.
+enum ColorCheck {
+    Is(String),
+    Any(bool),
+}
+
+impl ColorCheck {
+    fn parse(key: &str, value: &Value) -> Result<Self, Error> {
+        match value {
+            Value::Bool(any) => Ok(ColorCheck::Any(*any)),
+            Value::Null => Ok(ColorCheck::Any(false)),
+            Value::String(color) => rgb(color)
+                .map(ColorCheck::Is)
+                .ok_or_else(|| Error::msg(format!("Invalid '{}' color '{}', expected a hex color such as \"FFFF00\"", key, color))),
+            other => Err(Error::msg(format!("Invalid '{}' option {}", key, other))),
+        }
+    }
+
+    fn is_met(&self, color: Option<&String>) -> bool {
+        match self {
+            ColorCheck::Is(expected) => color == Some(expected),
+            ColorCheck::Any(any) => color.is_some() == *any,
+        }
+    }
+}
+
+/// A check against the style of a cell, e.g. {"strikethrough": true} or {"fill_color": "FFFF00"}. Every key given must match.
+#[derive(Default)]
+pub struct StyleCheck {
+    flags: Vec<(&'static str, bool)>,
+    fill: Option<ColorCheck>,
+    font_color: Option<ColorCheck>,
+}
+
+impl StyleCheck {
+    /// None when the object has no style key.
+    pub fn parse(obj: &Map<String, Value>) -> Result<Option<Self>, Error> {
+        let mut check = StyleCheck::default();
+        for key in ["bold", "italic", "underline", "strikethrough"] {
+            if let Some(value) = obj.get(key) {
+                let expected = value.as_bool().ok_or_else(|| Error::msg(format!("Invalid '{}' option {}", key, value)))?;
+                check.flags.push((key, expected));
+            }
+        }
+        // "fill" is kept as an alias of "fill_color", the key of the reported style
+        if let Some((key, value)) = ["fill_color", "fill"].into_iter().find_map(|key| obj.get(key).map(|value| (key, value))) {
+            check.fill = Some(ColorCheck::parse(key, value)?);
+        }
+        if let Some(value) = obj.get("font_color") {
+            check.font_color = Some(ColorCheck::parse("font_color", value)?);
+        }
+        let is_empty = check.flags.is_empty() && check.fill.is_none() && check.font_color.is_none();
+        Ok(if is_empty { None } else { Some(check) })
+    }
+
+    pub fn is_met(&self, style: &CellStyle) -> bool {
+        self.flags.iter().all(|(key, expected)| {
+            let actual = match *key {
+                "bold" => style.bold,
+                "italic" => style.italic,
+                "underline" => style.underline,
+                _ => style.strikethrough,
+            };
+            actual == *expected
+        })
+            && self.fill.as_ref().is_none_or(|fill| fill.is_met(style.fill_color.as_ref()))
+            && self.font_color.as_ref().is_none_or(|font_color| font_color.is_met(style.font_color.as_ref()))
+    }
+}
+
+/// Lists the cells of the sheet whose style matches the "style" check, e.g. {"fill_color": "FFFF00"} for yellow input cells,
+/// with their address, value and style, optionally limited to a "range". Formatted empty cells are listed as well.
+pub fn extract_styled_cells(sheet: &Range<Data>, instructions: &Map<String, Value>, context: &ExtractionContext) -> Result<IndexMap<String, Value>, Error> {
+    let check = match instructions.get("style") {
+        Some(Value::Object(obj)) => StyleCheck::parse(obj)?.ok_or_else(|| Error::msg("'style' requires a style such as 'fill' or 'bold'"))?,
+        Some(_) => return Err(Error::msg("Invalid 'style'")),
+        None => return Err(Error::msg("Missing 'style'")),
+    };
+    let bounds = match instructions.get("range") {
+        Some(range) => {
+            let range = range.as_str().ok_or_else(|| Error::msg("Invalid 'range'"))?;
+            let reference = context.resolve_reference(range)?;
+            if reference.sheet.as_deref().is_some_and(|name| name != context.sheet_name) {
+                return Err(Error::msg(format!("'range' should refer to sheet '{}'", context.sheet_name)));
+            }
+            Some(reference.bounds(sheet))
+        },
+        None => None,
+    };
+    let reader = CellReader::parse(instructions)?;
+    let styles = match context.styles(None)? {
+        Some(styles) => styles,
+        None => return Ok(IndexMap::new()),
+    };
+
+    let mut records = Vec::new();
+    for (row, col) in styles.cells() {
+        if let Some((start_row, end_row, start_col, end_col)) = bounds {
+            if !(start_row..=end_row).contains(row) || !(start_col..=end_col).contains(col) {
+                continue;
+            }
+        }
+        let style = styles.style((*row, *col));
+        if !check.is_met(style) {
+            continue;
+        }
+        let mut record = Map::new();
+        record.insert("address".to_string(), Value::String(format!("{}{}", conversions::column_index_to_name(*col), row)));
+        record.insert("value".to_string(), reader.read(sheet, *row, *col)?);
+        record.insert("style".to_string(), style.to_value());
+        records.push(Value::Object(record));
+    }
+
+    let mut results = IndexMap::new();
+    results.insert("data".to_string(), Value::Array(records));
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::io::{Cursor, Write};
+    use zip::write::SimpleFileOptions;
+
+    // A package with a workbook and a theme, the parts Palette::load reads
+    fn parts_with_theme(colors: &str) -> XlsxParts {
+        let files = [
+            ("_rels/.rels", r#"<Relationships><Relationship Id="rId1" Type="http://schemas.openxmlformats.org/officeDocument/2006/relationships/officeDocument" Target="xl/workbook.xml"/></Relationships>"#.to_string()),
+            ("xl/workbook.xml", "<workbook><sheets/></workbook>".to_string()),
+            ("xl/_rels/workbook.xml.rels", r#"<Relationships><Relationship Id="rId1" Type="http://schemas.openxmlformats.org/officeDocument/2006/relationships/theme" Target="theme/theme1.xml"/></Relationships>"#.to_string()),
+            ("xl/theme/theme1.xml", format!("<a:theme><a:themeElements><a:clrScheme>{}</a:clrScheme></a:themeElements></a:theme>", colors)),
+        ];
+        let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, content) in files {
+            writer.start_file(name, SimpleFileOptions::default()).unwrap();
+            writer.write_all(content.as_bytes()).unwrap();
+        }
+        XlsxParts::read(Cursor::new(writer.finish().unwrap().into_inner())).unwrap()
+    }
+
+    fn color_element(xml: &str) -> Element {
+        xlsx_parts::parse_xml(xml, &[]).unwrap()
+    }
+
+    #[test]
+    fn rgb_normalizes_colors() {
+        assert_eq!(rgb("FFFFFF00").as_deref(), Some("FFFF00"));
+        assert_eq!(rgb("#ff0000").as_deref(), Some("FF0000"));
+        assert_eq!(rgb("FFF"), None);
+        assert_eq!(rgb("GG0000"), None);
+    }
+
+    #[test]
+    fn apply_tint_matches_excel_shades() {
+        // "Blue, Accent 1" of the Office theme, lighter 80%, 60% and 40%, darker 25% and 50%
+        assert_eq!(apply_tint("4472C4", 0.7999816888943144), "DAE3F3");
+        assert_eq!(apply_tint("4472C4", 0.5999938962981048), "B4C7E7");
+        assert_eq!(apply_tint("4472C4", 0.3999755851924192), "8FAADC");
+        assert_eq!(apply_tint("4472C4", -0.249977111117893), "2F5597");
+        assert_eq!(apply_tint("4472C4", -0.499984740745262), "203864");
+        // Grays keep their hue
+        assert_eq!(apply_tint("FFFFFF", -0.0499893185216834), "F2F2F2");
+        assert_eq!(apply_tint("000000", 0.499984740745262), "7F7F7F");
+    }
+
+    #[test]
+    fn palette_swaps_light_and_dark_theme_colors() {
+        let parts = parts_with_theme(concat!(
+            r#"<a:dk1><a:sysClr val="windowText" lastClr="000000"/></a:dk1>"#,
+            r#"<a:lt1><a:sysClr val="window" lastClr="FFFFFF"/></a:lt1>"#,
+            r#"<a:dk2><a:srgbClr val="44546A"/></a:dk2>"#,
+            r#"<a:lt2><a:srgbClr val="E7E6E6"/></a:lt2>"#,
+            r#"<a:accent1><a:srgbClr val="4472C4"/></a:accent1>"#,
+        ));
+        let palette = Palette::load(&parts, &color_element("<styleSheet/>")).unwrap();
+        let color = |xml: &str| palette.color(&color_element(xml));
+        assert_eq!(color(r#"<color theme="0"/>"#).as_deref(), Some("FFFFFF"));
+        assert_eq!(color(r#"<color theme="1"/>"#).as_deref(), Some("000000"));
+        assert_eq!(color(r#"<color theme="2"/>"#).as_deref(), Some("E7E6E6"));
+        assert_eq!(color(r#"<color theme="3"/>"#).as_deref(), Some("44546A"));
+        assert_eq!(color(r#"<color theme="4" tint="0.5999938962981048"/>"#).as_deref(), Some("B4C7E7"));
+        assert_eq!(color(r#"<color theme="9"/>"#), None);
+        assert_eq!(color(r#"<color indexed="10"/>"#).as_deref(), Some("FF0000"));
+        assert_eq!(color(r#"<color rgb="FF00B050"/>"#).as_deref(), Some("00B050"));
+        assert_eq!(color(r#"<color auto="1"/>"#), None);
+    }
+
+    #[test]
+    fn style_check_accepts_fill_as_an_alias_of_fill_color() {
+        let yellow = CellStyle { fill_color: Some("FFFF00".to_string()), ..CellStyle::default() };
+        for check in [json!({"fill_color": "FFFF00"}), json!({"fill": "ffff00"})] {
+            let check = StyleCheck::parse(check.as_object().unwrap()).unwrap().unwrap();
+            assert!(check.is_met(&yellow));
+            assert!(!check.is_met(&CellStyle::default()));
+        }
+        assert!(StyleCheck::parse(json!({"fill_color": "yellow"}).as_object().unwrap()).is_err());
+    }
+
+    #[test]
+    fn cell_formats_are_read_once_per_workbook() {
+        let parts = parts_with_theme("");
+        let formats = parts.cell_formats().unwrap();
+        assert!(Rc::ptr_eq(&formats, &parts.cell_formats().unwrap()));
+        // A workbook without a styles part still has the default format
+        assert!(formats.len() == 1 && formats[0] == CellStyle::default());
+    }
+}
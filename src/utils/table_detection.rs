@@ -0,0 +1,193 @@
+use anyhow::{Result, Error};
+use calamine::{Range, Data};
+use serde_json::{Map, Value};
+use indexmap::IndexMap;
+use crate::utils::{conversions, dataframe};
+use crate::utils::context::ExtractionContext;
+use crate::utils::reader::CellReader;
+
+/// A rectangular block of cells, with absolute 0-based bounds (inclusive).
+#[derive(Clone, Copy)]
+struct Block {
+    start_row: u32,
+    end_row: u32,
+    start_col: u32,
+    end_col: u32,
+}
+
+impl Block {
+    fn rows(&self) -> u32 {
+        self.end_row - self.start_row + 1
+    }
+
+    fn columns(&self) -> u32 {
+        self.end_col - self.start_col + 1
+    }
+
+    fn address(&self) -> String {
+        format!(
+            "{}{}:{}{}",
+            conversions::column_index_to_name(self.start_col), self.start_row + 1,
+            conversions::column_index_to_name(self.end_col), self.end_row + 1
+        )
+    }
+}
+
+fn is_filled(sheet: &Range<Data>, row: u32, col: u32) -> bool {
+    match sheet.get_value((row, col)) {
+        None | Some(Data::Empty) => false,
+        Some(Data::String(s)) => !s.trim().is_empty(),
+        Some(_) => true,
+    }
+}
+
+fn row_is_blank(sheet: &Range<Data>, block: &Block, row: u32) -> bool {
+    (block.start_col..=block.end_col).all(|col| !is_filled(sheet, row, col))
+}
+
+fn column_is_blank(sheet: &Range<Data>, block: &Block, col: u32) -> bool {
+    (block.start_row..=block.end_row).all(|row| !is_filled(sheet, row, col))
+}
+
+// Shrinks the block to the bounding box of its filled cells, or None if it has none
+fn trim(sheet: &Range<Data>, mut block: Block) -> Option<Block> {
+    while block.start_row <= block.end_row && row_is_blank(sheet, &block, block.start_row) {
+        block.start_row += 1;
+    }
+    while block.end_row > block.start_row && row_is_blank(sheet, &block, block.end_row) {
+        block.end_row -= 1;
+    }
+    if block.start_row > block.end_row {
+        return None;
+    }
+    while column_is_blank(sheet, &block, block.start_col) {
+        block.start_col += 1;
+    }
+    while column_is_blank(sheet, &block, block.end_col) {
+        block.end_col -= 1;
+    }
+    Some(block)
+}
+
+// Splits the block into runs of non-blank rows (or columns) separated by blank ones
+fn split(block: &Block, by_rows: bool, is_blank: impl Fn(u32) -> bool) -> Vec<Block> {
+    let (start, end) = if by_rows { (block.start_row, block.end_row) } else { (block.start_col, block.end_col) };
+    let mut parts = Vec::new();
+    let mut run_start: Option<u32> = None;
+    for index in start..=end + 1 {
+        let blank = index > end || is_blank(index);
+        match (blank, run_start) {
+            (false, None) => run_start = Some(index),
+            (true, Some(first)) => {
+                let mut part = *block;
+                if by_rows {
+                    part.start_row = first;
+                    part.end_row = index - 1;
+                } else {
+                    part.start_col = first;
+                    part.end_col = index - 1;
+                }
+                parts.push(part);
+                run_start = None;
+            },
+            _ => (),
+        }
+    }
+    parts
+}
+
+// Recursive XY-cut: alternately split on fully blank rows and columns until no block can be split further
+fn find_blocks(sheet: &Range<Data>, block: Block, blocks: &mut Vec<Block>) {
+    let block = match trim(sheet, block) {
+        Some(block) => block,
+        None => return,
+    };
+    let row_parts = split(&block, true, |row| row_is_blank(sheet, &block, row));
+    if row_parts.len() > 1 {
+        for part in row_parts {
+            find_blocks(sheet, part, blocks);
+        }
+        return;
+    }
+    let column_parts = split(&block, false, |col| column_is_blank(sheet, &block, col));
+    if column_parts.len() > 1 {
+        for part in column_parts {
+            find_blocks(sheet, part, blocks);
+        }
+        return;
+    }
+    blocks.push(block);
+}
+
+// A header row only holds text, while data rows usually hold numbers or dates
+fn is_header_like(sheet: &Range<Data>, block: &Block, row: u32) -> bool {
+    let mut has_text = false;
+    for col in block.start_col..=block.end_col {
+        match sheet.get_value((row, col)) {
+            None | Some(Data::Empty) => (),
+            Some(Data::String(_)) => has_text = true,
+            Some(_) => return false,
+        }
+    }
+    has_text
+}
+
+fn count_header_rows(sheet: &Range<Data>, block: &Block, max_header_rows: u32) -> u32 {
+    let header_rows = (block.start_row..=block.end_row)
+        .take_while(|row| is_header_like(sheet, block, *row))
+        .count() as u32;
+    if header_rows == block.rows() {
+        // An all-text table: assume a single header row above the data
+        return if block.rows() > 1 { 1 } else { 0 };
+    }
+    header_rows.min(max_header_rows)
+}
+
+/// Detects rectangular tables separated by blank rows or columns, and extracts each one like `dataframe`.
+pub fn detect_tables(sheet: &Range<Data>, instructions: &Map<String, Value>, context: &ExtractionContext) -> Result<IndexMap<String, Value>, Error> {
+    let min_rows = instructions.get("min_rows").and_then(Value::as_u64).unwrap_or(2) as u32;
+    let min_columns = instructions.get("min_columns").and_then(Value::as_u64).unwrap_or(2) as u32;
+    let max_header_rows = instructions.get("max_header_rows").and_then(Value::as_u64).unwrap_or(3) as u32;
+    let separator = instructions.get("separator").and_then(Value::as_str).unwrap_or(" ");
+    let reader = CellReader::parse(instructions)?;
+
+    // An optional "range" limits the scan, by default the whole used range is scanned
+    let reference = match instructions.get("range") {
+        Some(range) => {
+            let range = range.as_str().ok_or_else(|| Error::msg("Invalid 'range'"))?;
+            Some(context.resolve_reference(range)?)
+        },
+        None => None,
+    };
+    let sheet = context.worksheet(sheet, reference.as_ref().and_then(|reference| reference.sheet.as_deref()));
+
+    let mut results = IndexMap::new();
+    if sheet.is_empty() {
+        return Ok(results);
+    }
+    let (start_row, end_row, start_col, end_col) = match &reference {
+        Some(reference) => reference.bounds(sheet),
+        None => {
+            let (start, end) = (sheet.start().unwrap_or((0, 0)), sheet.end().unwrap_or((0, 0)));
+            (start.0 + 1, end.0 + 1, start.1, end.1)
+        },
+    };
+    let mut blocks = Vec::new();
+    // Blocks use 0-based rows
+    find_blocks(sheet, Block { start_row: start_row - 1, end_row: end_row - 1, start_col, end_col }, &mut blocks);
+
+    for block in blocks.iter().filter(|block| block.rows() >= min_rows && block.columns() >= min_columns) {
+        let header_rows = count_header_rows(sheet, block, max_header_rows);
+        // The dataframe functions use 1-based rows
+        let header_indices: Vec<u32> = (0..header_rows).map(|offset| block.start_row + offset + 1).collect();
+        let data_rows = (block.start_row + header_rows + 1, block.end_row + 1);
+        let data = dataframe::build_dataframe(sheet, &header_indices, (block.start_col, block.end_col), data_rows, separator, &reader, true)?;
+
+        let mut table = Map::new();
+        table.insert("range".to_string(), Value::String(block.address()));
+        table.insert("header_rows".to_string(), Value::Array(header_indices.into_iter().map(Value::from).collect()));
+        table.insert("data".to_string(), data.into_iter().collect());
+        results.insert(format!("table_{}", results.len() + 1), Value::Object(table));
+    }
+    Ok(results)
+}
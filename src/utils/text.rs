@@ -0,0 +1,135 @@
+use anyhow::{Result, Error};
+use serde_json::Value;
+use unicode_normalization::UnicodeNormalization;
+
+#[derive(Clone, Copy, PartialEq)]
+enum UnicodeForm {
+    Nfc,
+    Nfkc,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Whitespace {
+    Trim,
+    Collapse,
+    Preserve,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Case {
+    Lower,
+    Upper,
+}
+
+/// Normalization of the text of string cells, configured separately for values and headers.
+#[derive(Clone)]
+pub struct TextOptions {
+    unicode: Option<UnicodeForm>,
+    whitespace: Whitespace,
+    // Line breaks become spaces, which headers have always done
+    newlines: bool,
+    strip_invisible: bool,
+    straight_quotes: bool,
+    case: Option<Case>,
+}
+
+impl TextOptions {
+    /// Values are trimmed by default.
+    pub fn values() -> Self {
+        TextOptions {
+            unicode: None,
+            whitespace: Whitespace::Trim,
+            newlines: false,
+            strip_invisible: false,
+            straight_quotes: false,
+            case: None,
+        }
+    }
+
+    /// Headers are trimmed, with line breaks replaced by spaces, by default.
+    pub fn headers() -> Self {
+        TextOptions { newlines: true, ..TextOptions::values() }
+    }
+
+    /// Reads the options from an object such as {"unicode": "NFKC", "whitespace": "collapse"},
+    /// or "exact" to keep the text as it is in the cell.
+    pub fn parse(value: Option<&Value>, default: TextOptions) -> Result<Self, Error> {
+        let obj = match value {
+            None => return Ok(default),
+            Some(Value::String(s)) if s == "exact" => {
+                return Ok(TextOptions { whitespace: Whitespace::Preserve, newlines: false, ..TextOptions::values() });
+            },
+            Some(Value::Object(obj)) => obj,
+            Some(other) => return Err(Error::msg(format!("Invalid text normalization {}", other))),
+        };
+        let mut options = default;
+        for (key, option) in obj {
+            match (key.as_str(), option) {
+                ("unicode", Value::String(form)) => options.unicode = match form.to_uppercase().as_str() {
+                    "NFC" => Some(UnicodeForm::Nfc),
+                    "NFKC" => Some(UnicodeForm::Nfkc),
+                    _ => return Err(Error::msg(format!("Invalid 'unicode' form '{}'", form))),
+                },
+                ("whitespace", Value::String(mode)) => {
+                    options.whitespace = match mode.as_str() {
+                        "trim" => Whitespace::Trim,
+                        "collapse" => Whitespace::Collapse,
+                        "preserve" => Whitespace::Preserve,
+                        _ => return Err(Error::msg(format!("Invalid 'whitespace' option '{}'", mode))),
+                    };
+                    if options.whitespace == Whitespace::Preserve {
+                        options.newlines = false;
+                    }
+                },
+                ("strip_invisible", Value::Bool(strip)) => options.strip_invisible = *strip,
+                ("straight_quotes", Value::Bool(straight)) => options.straight_quotes = *straight,
+                ("case", Value::String(case)) => options.case = match case.as_str() {
+                    "lower" => Some(Case::Lower),
+                    "upper" => Some(Case::Upper),
+                    "preserve" => None,
+                    _ => return Err(Error::msg(format!("Invalid 'case' option '{}'", case))),
+                },
+                _ => return Err(Error::msg(format!("Invalid text normalization option '{}'", key))),
+            }
+        }
+        Ok(options)
+    }
+
+    pub fn apply(&self, text: &str) -> String {
+        let mut text = text.to_string();
+        if self.strip_invisible {
+            // Non-breaking spaces become plain spaces, zero-width characters and soft hyphens are removed
+            text = text.chars()
+                .filter(|c| !matches!(c, '\u{200B}' | '\u{200C}' | '\u{200D}' | '\u{2060}' | '\u{FEFF}' | '\u{00AD}'))
+                .map(|c| if matches!(c, '\u{00A0}' | '\u{2007}' | '\u{202F}') { ' ' } else { c })
+                .collect();
+        }
+        text = match self.unicode {
+            Some(UnicodeForm::Nfc) => text.nfc().collect(),
+            Some(UnicodeForm::Nfkc) => text.nfkc().collect(),
+            None => text,
+        };
+        if self.straight_quotes {
+            text = text.chars()
+                .map(|c| match c {
+                    '\u{2018}' | '\u{2019}' | '\u{201A}' | '\u{201B}' => '\'',
+                    '\u{201C}' | '\u{201D}' | '\u{201E}' | '\u{201F}' => '"',
+                    c => c,
+                })
+                .collect();
+        }
+        if self.newlines {
+            text = text.replace("\r\n", " ").replace(['\n', '\r'], " ");
+        }
+        text = match self.whitespace {
+            Whitespace::Trim => text.trim().to_string(),
+            Whitespace::Collapse => text.split_whitespace().collect::<Vec<&str>>().join(" "),
+            Whitespace::Preserve => text,
+        };
+        match self.case {
+            Some(Case::Lower) => text.to_lowercase(),
+            Some(Case::Upper) => text.to_uppercase(),
+            None => text,
+        }
+    }
+}
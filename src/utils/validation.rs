@@ -0,0 +1,151 @@
+use anyhow::{Result, Error};
+use calamine::{Range, Data};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use crate::utils::{context, conversions, manipulations};
+use crate::utils::xlsx_parts::{Element, XlsxParts};
+
+/// A data validation list (dropdown) as written in the worksheet: the formula of its options and the cells it applies to.
+struct ListFormula {
+    formula: String,
+    sqref: String,
+    allow_blank: bool,
+}
+
+// Lists are written in <dataValidations>, or in an extension when their options are on another sheet (Excel 2010+)
+fn list_formulas(worksheet: &Element) -> Vec<ListFormula> {
+    let mut formulas = Vec::new();
+    let mut add = |validation: &Element, formula: Option<String>, sqref: Option<String>| {
+        if validation.attr("type") != Some("list") {
+            return;
+        }
+        if let (Some(formula), Some(sqref)) = (formula, sqref) {
+            let allow_blank = matches!(validation.attr("allowBlank"), Some("1") | Some("true"));
+            formulas.push(ListFormula { formula: formula.trim().to_string(), sqref, allow_blank });
+        }
+    };
+    if let Some(validations) = worksheet.child("dataValidations") {
+        for validation in validations.children("dataValidation") {
+            add(validation, validation.child("formula1").map(|f| f.text.clone()), validation.attr("sqref").map(str::to_string));
+        }
+    }
+    let extensions = worksheet.child("extLst").map(|list| list.children.as_slice()).unwrap_or_default();
+    for validations in extensions.iter().filter_map(|extension| extension.child("dataValidations")) {
+        for validation in validations.children("dataValidation") {
+            let formula = validation.child("formula1").and_then(|f| f.child("f")).map(|f| f.text.clone());
+            let sqref = validation.child("sqref").map(|sqref| sqref.text.clone());
+            add(validation, formula, sqref);
+        }
+    }
+    formulas
+}
+
+/// The sheets the data validation lists of the given sheets read their options from, so they can be loaded
+/// with the other referenced sheets. Lists without a sheet name read from their own sheet.
+/// Sheets that cannot be read are left out, their lists have no options.
+pub fn source_sheets(parts: &XlsxParts, defined_names: &[(String, String)], sheet_names: &[String]) -> Vec<String> {
+    let mut sheets = Vec::new();
+    for sheet_name in sheet_names {
+        let Ok(worksheet) = parts.worksheet(sheet_name, false) else {
+            continue;
+        };
+        for list in list_formulas(&worksheet) {
+            if list.formula.starts_with('"') {
+                continue;
+            }
+            if let Ok(reference) = context::resolve_reference(defined_names, &list.formula) {
+                let sheet = reference.sheet.unwrap_or_else(|| sheet_name.clone());
+                if !sheets.contains(&sheet) {
+                    sheets.push(sheet);
+                }
+            }
+        }
+    }
+    sheets
+}
+
+/// A data validation list with its options, which are None when they cannot be read (e.g. an INDIRECT formula).
+struct ListRule {
+    areas: Vec<(u32, u32, u32, u32)>,
+    options: Option<Vec<Value>>,
+    allow_blank: bool,
+}
+
+/// The data validation lists of a sheet, with their options.
+#[derive(Default)]
+pub struct Validations {
+    rules: Vec<ListRule>,
+}
+
+impl Validations {
+    /// Reads the lists of a sheet. Options given as a range (or a defined name) are read from the loaded sheets.
+    /// A list whose options cannot be read, or whose range is invalid, has no options (None) instead of failing.
+    pub fn load(parts: &XlsxParts, sheet_name: &str, defined_names: &[(String, String)], sheets: &HashMap<String, Range<Data>>) -> Result<Self, Error> {
+        let mut rules = Vec::new();
+        for list in list_formulas(&parts.worksheet(sheet_name, false)?) {
+            // Several areas are separated by spaces, e.g. "B2:B10 D5". An invalid area leaves the options of the list unknown.
+            let parsed: Vec<Option<(u32, u32, u32, u32)>> = list.sqref.split_whitespace()
+                .map(|area| {
+                    let reference = conversions::parse_reference(area).ok()?;
+                    Some((reference.start_row?, reference.end_row?, reference.start_col?, reference.end_col?))
+                })
+                .collect();
+            let valid_range = parsed.iter().all(Option::is_some);
+            let areas = parsed.into_iter().flatten().collect();
+            let options = match list.formula.strip_prefix('"').and_then(|formula| formula.strip_suffix('"')) {
+                _ if !valid_range => None,
+                // An explicit list such as "Yes,No"
+                Some(items) => Some(items.split(',').map(|item| Value::String(item.trim().to_string())).collect()),
+                None => match context::resolve_reference(defined_names, &list.formula) {
+                    Ok(reference) => {
+                        let source_name = reference.sheet.as_deref().unwrap_or(sheet_name);
+                        match sheets.iter().find(|(name, _)| name.eq_ignore_ascii_case(source_name)) {
+                            Some((_, source)) => {
+                                let (start_row, end_row, start_col, end_col) = reference.bounds(source);
+                                let mut options = Vec::new();
+                                for row in start_row..=end_row {
+                                    for col in start_col..=end_col {
+                                        // Error cells such as #N/A are not options
+                                        if let Ok((Some(value), _)) = manipulations::extract_cell_value(source, row, col, false) {
+                                            if !value.is_null() {
+                                                options.push(value);
+                                            }
+                                        }
+                                    }
+                                }
+                                Some(options)
+                            },
+                            None => None,
+                        }
+                    },
+                    Err(_) => None,
+                },
+            };
+            rules.push(ListRule { areas, options, allow_blank: list.allow_blank });
+        }
+        Ok(Validations { rules })
+    }
+
+    /// Checks a value against the list of its cell, as {"allowed", "valid"} with the "code" of the value
+    /// when a lookup is given. None when no list applies to the cell.
+    /// Options are compared case-insensitively, like Excel does.
+    pub fn check(&self, value: &Value, (row, col): (u32, u32), lookup: Option<&Map<String, Value>>) -> Option<Value> {
+        let rule = self.rules.iter().find(|rule| rule.areas.iter().any(|(start_row, end_row, start_col, end_col)| {
+            (*start_row..=*end_row).contains(&row) && (*start_col..=*end_col).contains(&col)
+        }))?;
+        let text = manipulations::value_text(value);
+        let valid = match &rule.options {
+            _ if text.is_empty() => Value::Bool(rule.allow_blank),
+            Some(options) => Value::Bool(options.iter().any(|option| manipulations::value_text(option).to_lowercase() == text.to_lowercase())),
+            None => Value::Null,
+        };
+        let mut map = Map::new();
+        map.insert("allowed".to_string(), rule.options.clone().map_or(Value::Null, Value::Array));
+        map.insert("valid".to_string(), valid);
+        if let Some(lookup) = lookup {
+            let code = lookup.get(&text).or_else(|| lookup.iter().find(|(label, _)| label.to_lowercase() == text.to_lowercase()).map(|(_, code)| code));
+            map.insert("code".to_string(), code.cloned().unwrap_or(Value::Null));
+        }
+        Some(Value::Object(map))
+    }
+}
@@ -0,0 +1,338 @@
+use anyhow::{Result, Error};
+use quick_xml::escape::unescape;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use std::cell::OnceCell;
+use std::collections::HashMap;
+use std::io::{Read, Seek};
+use std::rc::Rc;
+use zip::ZipArchive;
+use crate::utils::conversions;
+use crate::utils::styles::{self, CellStyle};
+
+/// An element of an xml part, with its local name (no namespace prefix), attributes, children and text.
+pub struct Element {
+    pub name: String,
+    attributes: Vec<(String, String)>,
+    pub children: Vec<Element>,
+    pub text: String,
+}
+
+impl Element {
+    fn new(name: String) -> Self {
+        Element { name, attributes: Vec::new(), children: Vec::new(), text: String::new() }
+    }
+
+    fn from_start(start: &BytesStart) -> Result<Self, Error> {
+        let mut element = Element::new(String::from_utf8_lossy(start.local_name().as_ref()).into_owned());
+        for attribute in start.attributes().with_checks(false) {
+            let attribute = attribute?;
+            let key = String::from_utf8_lossy(attribute.key.as_ref()).into_owned();
+            let raw = String::from_utf8_lossy(&attribute.value);
+            let value = unescape(&raw).map(|value| value.into_owned()).unwrap_or_else(|_| raw.to_string());
+            element.attributes.push((key, value));
+        }
+        Ok(element)
+    }
+
+    /// The value of an attribute, by qualified name ("r:id") or local name ("id").
+    pub fn attr(&self, name: &str) -> Option<&str> {
+        self.attributes.iter()
+            .find(|(key, _)| key == name)
+            .or_else(|| self.attributes.iter().find(|(key, _)| key.rsplit(':').next() == Some(name)))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn child(&self, name: &str) -> Option<&Element> {
+        self.children.iter().find(|child| child.name == name)
+    }
+
+    pub fn children<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Element> {
+        self.children.iter().filter(move |child| child.name == name)
+    }
+}
+
+/// An on/off property such as <b/>, which may also be written <b val="0"/>.
+pub fn flag(property: &Element) -> bool {
+    !matches!(property.attr("val"), Some("0") | Some("false"))
+}
+
+/// A cell reference of an xml part, e.g. "B2", as (1-based row, 0-based column).
+pub fn cell_position(address: &str) -> Result<(u32, u32), Error> {
+    conversions::parse_reference(address)?
+        .cell()
+        .ok_or_else(|| Error::msg(format!("Invalid cell reference '{}'", address)))
+}
+
+/// A cell element with its (1-based row, 0-based column).
+pub type CellElement<'a> = ((u32, u32), &'a Element);
+
+/// The cells of a worksheet parsed with its cells.
+pub fn sheet_cells(worksheet: &Element) -> Result<Vec<CellElement<'_>>, Error> {
+    let rows = worksheet.child("sheetData").map(|data| data.children.as_slice()).unwrap_or_default();
+    let mut cells = Vec::new();
+    let mut row_number = 0;
+    for row in rows.iter().filter(|row| row.name == "row") {
+        row_number = row.attr("r").and_then(|r| r.parse().ok()).unwrap_or(row_number + 1);
+        let mut col = 0;
+        for cell in row.children("c") {
+            // The cell reference is optional, cells without one follow the previous cell
+            let position = match cell.attr("r") {
+                Some(address) => cell_position(address)?,
+                None => (row_number, col),
+            };
+            col = position.1 + 1;
+            cells.push((position, cell));
+        }
+    }
+    Ok(cells)
+}
+
+// VML text is HTML, which may use &nbsp; besides the xml entities
+fn html_entity(entity: &str) -> Option<&'static str> {
+    match entity {
+        "nbsp" => Some("\u{a0}"),
+        _ => None,
+    }
+}
+
+/// Parses an xml part into a tree of elements, leaving out the elements named in `skip` (e.g. the cells of a worksheet).
+/// Legacy VML drawings are not always well-formed, so mismatched end tags are tolerated.
+pub fn parse_xml(xml: &str, skip: &[&str]) -> Result<Element, Error> {
+    let mut reader = Reader::from_str(xml);
+    reader.check_end_names(false);
+    let mut stack = vec![Element::new(String::new())];
+    loop {
+        match reader.read_event()? {
+            Event::Start(start) if skip.iter().any(|name| start.local_name().as_ref() == name.as_bytes()) => {
+                reader.read_to_end(start.name())?;
+            },
+            Event::Start(start) => stack.push(Element::from_start(&start)?),
+            Event::Empty(start) => {
+                let element = Element::from_start(&start)?;
+                stack.last_mut().unwrap().children.push(element);
+            },
+            Event::End(end) => {
+                // An end tag closes its element, and any element left open inside it such as <br> in VML text
+                let name = String::from_utf8_lossy(end.local_name().as_ref()).into_owned();
+                if let Some(index) = stack.iter().skip(1).rposition(|element| element.name == name) {
+                    while stack.len() > index + 1 {
+                        let element = stack.pop().unwrap();
+                        stack.last_mut().unwrap().children.push(element);
+                    }
+                }
+            },
+            Event::Text(text) => {
+                let text = text.unescape_with(html_entity)
+                    .map(|text| text.into_owned())
+                    .unwrap_or_else(|_| String::from_utf8_lossy(&text).into_owned());
+                stack.last_mut().unwrap().text.push_str(&text);
+            },
+            Event::CData(data) => stack.last_mut().unwrap().text.push_str(&String::from_utf8_lossy(&data)),
+            Event::Eof => break,
+            _ => (),
+        }
+    }
+    // Elements left open at the end of the part are closed
+    while stack.len() > 1 {
+        let element = stack.pop().unwrap();
+        stack.last_mut().unwrap().children.push(element);
+    }
+    stack.pop().unwrap().children.into_iter().next().ok_or_else(|| Error::msg("Empty xml part"))
+}
+
+/// A relationship of a part, e.g. from a worksheet to its comments.
+pub struct Relationship {
+    pub id: String,
+    /// The last segment of the relationship type, e.g. "comments" or "hyperlink".
+    pub kind: String,
+    /// The path of the target part, or the url of an external target.
+    pub target: String,
+    pub external: bool,
+}
+
+// Resolves a relationship target against the folder of the source part, e.g. "../comments1.xml" from "xl/worksheets/sheet1.xml"
+fn resolve_target(source: &str, target: &str) -> String {
+    if let Some(absolute) = target.strip_prefix('/') {
+        return absolute.to_string();
+    }
+    let mut segments: Vec<&str> = source.split('/').collect();
+    segments.pop();
+    for segment in target.split('/') {
+        match segment {
+            ".." => { segments.pop(); },
+            "." | "" => (),
+            segment => segments.push(segment),
+        }
+    }
+    segments.join("/")
+}
+
+/// The xml parts of an xlsx package, for what calamine does not read (comments, hyperlinks, rich text, data validations,
+/// form controls, cell styles).
+/// They are only loaded when an extraction needs them.
+pub struct XlsxParts {
+    parts: HashMap<String, String>,
+    workbook: String,
+    // Sheet name to worksheet part
+    sheets: HashMap<String, String>,
+    // The cell formats of styles.xml, read on first use
+    formats: OnceCell<Rc<Vec<CellStyle>>>,
+}
+
+impl XlsxParts {
+    pub fn read<R: Read + Seek>(reader: R) -> Result<Self, Error> {
+        let mut archive = ZipArchive::new(reader)?;
+        let mut parts = HashMap::new();
+        for index in 0..archive.len() {
+            let mut file = archive.by_index(index)?;
+            let name = file.name().to_string();
+            if [".xml", ".rels", ".vml"].iter().any(|extension| name.to_lowercase().ends_with(extension)) {
+                let mut content = Vec::with_capacity(file.size() as usize);
+                file.read_to_end(&mut content)?;
+                parts.insert(name, String::from_utf8_lossy(&content).into_owned());
+            }
+        }
+
+        let mut xlsx = XlsxParts { parts, workbook: String::new(), sheets: HashMap::new(), formats: OnceCell::new() };
+        xlsx.workbook = xlsx.relationships("")?
+            .into_iter()
+            .find(|relationship| relationship.kind == "officeDocument")
+            .map(|relationship| relationship.target)
+            .unwrap_or_else(|| "xl/workbook.xml".to_string());
+        let targets: HashMap<String, String> = xlsx.relationships(&xlsx.workbook)?
+            .into_iter()
+            .map(|relationship| (relationship.id, relationship.target))
+            .collect();
+        let workbook = xlsx.xml(&xlsx.workbook)?;
+        if let Some(sheets) = workbook.child("sheets") {
+            for sheet in sheets.children("sheet") {
+                if let (Some(name), Some(target)) = (sheet.attr("name"), sheet.attr("id").and_then(|id| targets.get(id))) {
+                    xlsx.sheets.insert(name.to_string(), target.clone());
+                }
+            }
+        }
+        Ok(xlsx)
+    }
+
+    /// The cell formats of the workbook, read once and shared by the styles of every sheet.
+    pub fn cell_formats(&self) -> Result<Rc<Vec<CellStyle>>, Error> {
+        if let Some(formats) = self.formats.get() {
+            return Ok(formats.clone());
+        }
+        let formats = Rc::new(styles::load_formats(self)?);
+        Ok(self.formats.get_or_init(|| formats).clone())
+    }
+
+        /// The text of a part, for reading large parts as a stream rather than as a tree.
+    pub fn part(&self, path: &str) -> Result<&str, Error> {
+        self.parts.get(path)
+            .map(String::as_str)
+            .ok_or_else(|| Error::msg(format!("Missing part '{}'", path)))
+    }
+
+    pub fn xml(&self, path: &str) -> Result<Element, Error> {
+        parse_xml(self.part(path)?, &[])
+    }
+
+    /// The worksheet part of a sheet, optionally without its cells, which are the bulk of the part.
+    pub fn worksheet(&self, sheet_name: &str, cells: bool) -> Result<Element, Error> {
+        let part = self.part(self.sheet_part(sheet_name)?)?;
+        parse_xml(part, if cells { &[] } else { &["sheetData"] })
+    }
+
+    /// The relationships of a part, from its "_rels" file. An empty path gives the package relationships.
+    pub fn relationships(&self, path: &str) -> Result<Vec<Relationship>, Error> {
+        let (folder, file) = path.rsplit_once('/').unwrap_or(("", path));
+        let rels_path = match folder {
+            "" => format!("_rels/{}.rels", file),
+            folder => format!("{}/_rels/{}.rels", folder, file),
+        };
+        if !self.parts.contains_key(&rels_path) {
+            return Ok(Vec::new());
+        }
+        let relationships = self.xml(&rels_path)?
+            .children("Relationship")
+            .filter_map(|relationship| {
+                let id = relationship.attr("Id")?.to_string();
+                let kind = relationship.attr("Type")?.rsplit('/').next()?.to_string();
+                let target = relationship.attr("Target")?;
+                let external = relationship.attr("TargetMode") == Some("External");
+                let target = if external { target.to_string() } else { resolve_target(path, target) };
+                Some(Relationship { id, kind, target, external })
+            })
+            .collect();
+        Ok(relationships)
+    }
+
+    /// The path of the worksheet part of a sheet.
+    pub fn sheet_part(&self, sheet_name: &str) -> Result<&str, Error> {
+        self.sheets.get(sheet_name)
+            .map(String::as_str)
+            .ok_or_else(|| Error::msg(format!("Sheet '{}' not found", sheet_name)))
+    }
+
+    /// The parts related to a part by a relationship of the given kind, e.g. the "comments" of a worksheet.
+    pub fn related(&self, path: &str, kind: &str) -> Result<Vec<String>, Error> {
+        Ok(self.relationships(path)?
+            .into_iter()
+            .filter(|relationship| relationship.kind == kind && !relationship.external)
+            .map(|relationship| relationship.target)
+            .collect())
+    }
+
+    /// The part related to the workbook by a relationship of the given kind, e.g. "sharedStrings".
+    pub fn workbook_part(&self, kind: &str) -> Result<Option<String>, Error> {
+        Ok(self.related(&self.workbook, kind)?.into_iter().next())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_xml_closes_unclosed_vml_tags() {
+        let vml = r#"<xml xmlns:v="urn:schemas-microsoft-com:vml"><v:shape id="_x0000_s1025"><v:textbox><div>Approved<br>by&nbsp;QA</div></v:textbox></v:shape></xml>"#;
+        let root = parse_xml(vml, &[]).unwrap();
+        let shape = root.child("shape").unwrap();
+        assert_eq!(shape.attr("id"), Some("_x0000_s1025"));
+        let div = shape.child("textbox").and_then(|textbox| textbox.child("div")).unwrap();
+        assert_eq!(div.text, "Approved");
+        assert_eq!(div.child("br").unwrap().text, "by\u{a0}QA");
+    }
+
+    #[test]
+    fn parse_xml_closes_elements_left_open_at_the_end() {
+        let root = parse_xml("<a><b x=\"1\"><c>text", &[]).unwrap();
+        let b = root.child("b").unwrap();
+        assert_eq!(b.attr("x"), Some("1"));
+        assert_eq!(b.child("c").unwrap().text, "text");
+    }
+
+    #[test]
+    fn parse_xml_skips_elements() {
+        let xml = r#"<worksheet xmlns:r="rels"><cols><col min="1" max="2"/></cols><sheetData><row r="1"><c r="A1"/></row></sheetData><legacyDrawing r:id="rId1"/></worksheet>"#;
+        let worksheet = parse_xml(xml, &["sheetData"]).unwrap();
+        assert!(worksheet.child("sheetData").is_none());
+        assert_eq!(worksheet.child("cols").unwrap().children("col").count(), 1);
+        // Attributes are found by qualified or local name
+        let drawing = worksheet.child("legacyDrawing").unwrap();
+        assert_eq!(drawing.attr("r:id"), Some("rId1"));
+        assert_eq!(drawing.attr("id"), Some("rId1"));
+    }
+
+    #[test]
+    fn resolve_target_relative_to_the_source_folder() {
+        assert_eq!(resolve_target("xl/workbook.xml", "worksheets/sheet1.xml"), "xl/worksheets/sheet1.xml");
+        assert_eq!(resolve_target("xl/worksheets/sheet1.xml", "../comments1.xml"), "xl/comments1.xml");
+        assert_eq!(resolve_target("xl/worksheets/sheet1.xml", "../../customXml/item1.xml"), "customXml/item1.xml");
+        assert_eq!(resolve_target("xl/worksheets/sheet1.xml", "./../drawings/vmlDrawing1.vml"), "xl/drawings/vmlDrawing1.vml");
+        assert_eq!(resolve_target("", "xl/workbook.xml"), "xl/workbook.xml");
+    }
+
+    #[test]
+    fn resolve_target_absolute() {
+        assert_eq!(resolve_target("xl/worksheets/sheet1.xml", "/xl/comments/comment1.xml"), "xl/comments/comment1.xml");
+    }
+}